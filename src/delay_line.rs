@@ -0,0 +1,109 @@
+use nih_plug::prelude::NoteEvent;
+use crate::timed_queue::{Timed, TimedQueue};
+
+/// The maximum number of events that can be held back at the same time.
+pub const MAX_DELAYED: usize = 4096;
+
+/// An incoming event that is handled later, to look ahead at the events that follow it.
+#[derive(Debug, Clone, Copy)]
+pub struct DelayedEvent {
+    /// The sample this event should be handled at, counted from when the plugin was created.
+    pub due: u64,
+    pub event: NoteEvent<()>,
+}
+
+impl Timed for DelayedEvent {
+    fn due(&self) -> u64 {
+        self.due
+    }
+}
+
+/// Holds incoming events back by the plugin's latency.
+pub type DelayLine = TimedQueue<DelayedEvent, MAX_DELAYED>;
+
+impl DelayLine {
+    /// Remove and return the earliest NoteOn with this channel and pitch that is due at or before
+    /// `until`, so it can be handled together with an earlier note.
+    pub fn take_note_on(&mut self, channel: u8, note: u8, until: u64) -> Option<DelayedEvent> {
+        self.take_first(|delayed| delayed.due <= until && matches!(
+            delayed.event,
+            NoteEvent::NoteOn { channel: c, note: n, .. } if c == channel && n == note
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> NoteEvent<()> {
+        NoteEvent::NoteOn { timing: 0, voice_id: None, channel: 0, note, velocity: 1.0 }
+    }
+
+    fn note(delayed: DelayedEvent) -> u8 {
+        match delayed.event {
+            NoteEvent::NoteOn { note, .. } | NoteEvent::NoteOff { note, .. } => note,
+            _ => panic!("not a note event"),
+        }
+    }
+
+    #[test]
+    fn events_come_out_in_time_order() {
+        let mut delay_line = DelayLine::default();
+        delay_line.push(DelayedEvent { due: 20, event: note_on(2) });
+        delay_line.push(DelayedEvent { due: 10, event: note_on(1) });
+        delay_line.push(DelayedEvent { due: 30, event: note_on(3) });
+
+        // `until` is exclusive
+        assert!(delay_line.pop_due(10).is_none());
+        assert_eq!(note(delay_line.pop_due(31).unwrap()), 1);
+        assert_eq!(note(delay_line.pop_due(31).unwrap()), 2);
+        assert_eq!(note(delay_line.pop_due(31).unwrap()), 3);
+        assert!(delay_line.is_empty());
+    }
+
+    #[test]
+    fn events_at_the_same_sample_keep_their_order() {
+        let mut delay_line = DelayLine::default();
+        delay_line.push(DelayedEvent { due: 10, event: note_on(1) });
+        delay_line.push(DelayedEvent {
+            due: 10,
+            event: NoteEvent::NoteOff { timing: 0, voice_id: None, channel: 0, note: 2, velocity: 0.0 },
+        });
+        delay_line.push(DelayedEvent { due: 10, event: note_on(3) });
+
+        assert_eq!(note(delay_line.pop_due(11).unwrap()), 1);
+        assert_eq!(note(delay_line.pop_due(11).unwrap()), 2);
+        assert_eq!(note(delay_line.pop_due(11).unwrap()), 3);
+    }
+
+    #[test]
+    fn take_note_on_only_looks_within_the_window() {
+        let mut delay_line = DelayLine::default();
+        delay_line.push(DelayedEvent { due: 50, event: note_on(40) });
+
+        assert!(delay_line.take_note_on(0, 40, 49).is_none());
+        assert!(delay_line.take_note_on(1, 40, 50).is_none());
+        assert_eq!(delay_line.take_note_on(0, 40, 50).unwrap().due, 50);
+        assert!(delay_line.is_empty());
+    }
+
+    #[test]
+    fn last_due_is_the_latest_event() {
+        let mut delay_line = DelayLine::default();
+        assert_eq!(delay_line.last_due(), None);
+
+        delay_line.push(DelayedEvent { due: 30, event: note_on(1) });
+        delay_line.push(DelayedEvent { due: 20, event: note_on(2) });
+        assert_eq!(delay_line.last_due(), Some(30));
+    }
+
+    #[test]
+    fn full_delay_line_rejects_events() {
+        let mut delay_line = DelayLine::default();
+        for due in 0..MAX_DELAYED as u64 {
+            assert!(delay_line.push(DelayedEvent { due, event: note_on(1) }));
+        }
+        assert!(!delay_line.push(DelayedEvent { due: 0, event: note_on(1) }));
+    }
+}
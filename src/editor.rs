@@ -1,11 +1,13 @@
-use nih_plug::prelude::{Editor};
+use nih_plug::prelude::{Editor, Param};
 use vizia_plug::vizia::prelude::*;
 use vizia_plug::{create_vizia_editor, ViziaState, ViziaTheming};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use vizia_plug::vizia::style::FontWeightKeyword::Bold;
-use vizia_plug::widgets::{ParamButton};
-use crate::params::{RangeParams, VelocityMapperParams};
+use vizia_plug::widgets::{ParamButton, ParamButtonExt, ParamEvent, ParamSlider};
+use crate::params::{OverlapMode, RangeParams, VelocityMapperParams, MAX_COMBINE_RULES, MAX_CROSSTALK_GROUPS, MAX_EXTRA_CHANNELS, MAX_GROUP_PADS, MAX_LAYERS, MAX_MESSAGES, MAX_RANGES};
 use crate::gui::dropdown_param::DropDownParam;
+use crate::scale::{SCALE_STEPS, STEP_NAMES};
 
 const NEW_STYLE: &str = r#"
     .red_button:checked {
@@ -16,22 +18,46 @@ const NEW_STYLE: &str = r#"
 #[derive(Lens, Clone)]
 pub(crate) struct Data {
     pub(crate) params: Arc<VelocityMapperParams>,
-    // pub(crate) how_many: usize,
+    pub(crate) panic: Arc<AtomicBool>,
+}
+
+pub(crate) enum AppEvent {
+    AddRange,
+    RemoveRange,
+    Panic,
 }
 
 impl Model for Data {
-    fn event(&mut self, _: &mut EventContext, event: &mut Event) {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|app_event, _| match app_event {
+            AppEvent::AddRange => self.set_how_many(cx, self.params.how_many.value() + 1),
+            AppEvent::RemoveRange => self.set_how_many(cx, self.params.how_many.value() - 1),
+            AppEvent::Panic => self.panic.store(true, Ordering::Relaxed),
+        });
+    }
+}
+
+impl Data {
+    /// Change the number of active ranges through the host, so it ends up in the saved state.
+    fn set_how_many(&self, cx: &mut EventContext, how_many: i32) {
+        let param = &self.params.how_many;
+        let how_many = how_many.clamp(1, MAX_RANGES as i32);
+
+        cx.emit(ParamEvent::BeginSetParameter(param).upcast());
+        cx.emit(ParamEvent::SetParameter(param, how_many).upcast());
+        cx.emit(ParamEvent::EndSetParameter(param).upcast());
     }
 }
 
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (300, 570))
+    ViziaState::new(|| (600, 570))
 }
 
 pub(crate) fn create(
     params: Arc<VelocityMapperParams>,
     editor_state: Arc<ViziaState>,
+    panic: Arc<AtomicBool>,
 ) -> Option<Box<dyn Editor>> {
     create_vizia_editor(editor_state, ViziaTheming::Custom, move |cx, _| {
         // add new styling
@@ -39,39 +65,249 @@ pub(crate) fn create(
 
         Data {
             params: params.clone(),
-            //how_many: params.how_many.value() as usize,
+            panic: panic.clone(),
         }
             .build(cx);
 
-        VStack::new(cx, |cx| {
-            Label::new(cx, "Velocity Mapper")
-                .font_weight(Bold)
-                .font_size(25.0);
+        HStack::new(cx, |cx| {
+            // Global settings
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Velocity Mapper")
+                    .font_weight(Bold)
+                    .font_size(25.0);
+
+                Element::new(cx).height(Pixels(15.0));
+
+                Label::new(cx, "Settings:")
+                    .font_weight(Bold)
+                    .font_size(15.0);
+
+                Element::new(cx).height(Pixels(5.0));
+
+                ScrollView::new(cx, |cx| {
+                    VStack::new(cx, |cx| {
+                        setting(cx, "Note-Off Matching:", |params| &params.note_off_matching);
+                        setting(cx, "Overlapping Ranges:", |params| &params.overlap);
+                        setting(cx, "Unmatched Notes:", |params| &params.unmatched);
+                        setting(cx, "Random Seed:", |params| &params.seed);
+                        setting(cx, "Scale:", |params| &params.scale);
+                        setting(cx, "Scale Root:", |params| &params.scale_root);
+
+                        // Steps of the custom scale
+                        HStack::new(cx, |cx| {
+                            for step in 0..SCALE_STEPS {
+                                ParamButton::new(cx, Data::params, move |params| &params.scale_steps[step].active)
+                                    .with_label(STEP_NAMES[step].to_string())
+                                    .font_size(9.0)
+                                    .width(Pixels(22.0))
+                                    .height(Pixels(22.0));
+                            }
+                        })
+                            .height(Pixels(30.0))
+                            .gap(Pixels(1.0))
+                            .alignment(Alignment::Center);
+
+                        setting(cx, "Velocity Controller:", |params| &params.velocity_cc_mode);
+                        setting(cx, "Velocity CC:", |params| &params.velocity_cc);
+                        setting(cx, "Controller Source:", |params| &params.velocity_cc_source);
+                        setting(cx, "Choke Mode:", |params| &params.choke_mode);
+                        setting(cx, "Pedal CC:", |params| &params.pedal_cc);
+                        setting(cx, "Retrigger Window:", |params| &params.retrigger_ms);
+                        setting(cx, "Retrigger Mode:", |params| &params.retrigger_mode);
+                        setting(cx, "Crosstalk Window:", |params| &params.crosstalk_ms);
+                        setting(cx, "Crosstalk Ratio:", |params| &params.crosstalk_ratio);
+
+                        for group in 0..MAX_CROSSTALK_GROUPS {
+                            crosstalk_group(cx, group);
+                        }
+
+                        setting(cx, "Combine Window:", |params| &params.combine_ms);
+
+                        for rule in 0..MAX_COMBINE_RULES {
+                            combine_rule(cx, rule);
+                        }
+                    })
+                        .height(Auto);
+                })
+                    .show_horizontal_scrollbar(false)
+                    .width(Stretch(1.0))
+                    .height(Stretch(1.0));
+
+                Element::new(cx).height(Pixels(10.0));
+
+                // Release all notes that are still held
+                Button::new(cx, |cx| Label::new(cx, "Panic"))
+                    .on_press(|cx| cx.emit(AppEvent::Panic))
+                    .width(Pixels(80.0));
+
+                Element::new(cx).height(Pixels(10.0));
+            })
+                .width(Pixels(300.0))
+                .alignment(Alignment::TopCenter);
+
+            // Mappings
+            VStack::new(cx, |cx| {
+                Element::new(cx).height(Pixels(10.0));
+
+                // Add or remove ranges
+                HStack::new(cx, |cx| {
+                    Label::new(cx, "Mappings:")
+                        .font_size(15.0);
 
-            Element::new(cx).height(Pixels(15.0));
+                    Button::new(cx, |cx| Label::new(cx, "-"))
+                        .on_press(|cx| cx.emit(AppEvent::RemoveRange))
+                        .width(Pixels(25.0));
 
-            // DropDownParam::new(cx, Data::params, |params| &params.how_many);
+                    Label::new(cx, Data::params.map(|params| params.how_many.value().to_string()))
+                        .font_size(15.0)
+                        .width(Pixels(25.0))
+                        .alignment(Alignment::Center);
 
-            //ScrollView::new(cx, |cx| {
+                    Button::new(cx, |cx| Label::new(cx, "+"))
+                        .on_press(|cx| cx.emit(AppEvent::AddRange))
+                        .width(Pixels(25.0));
+                })
+                    .height(Pixels(30.0))
+                    .gap(Pixels(5.0))
+                    .alignment(Alignment::Center);
+
+                Element::new(cx).height(Pixels(10.0));
+
+                ScrollView::new(cx, |cx| {
+                    Binding::new(cx, Data::params.map(|params| params.how_many.value() as usize), |cx, how_many| {
+                        for x in 0..how_many.get(cx) {
+                            range_selector(
+                                cx,
+                                Data::params,
+                                move |params| &params.ranges[x],
+                                &(x+1).to_string(),
+                                Data::params.map(move |params| priority_label(params, x)),
+                            );
+
+                            Element::new(cx).height(Pixels(10.0));
+                        }
+                    });
+                })
+                    .show_horizontal_scrollbar(false)
+                    .width(Stretch(1.0))
+                    .height(Stretch(1.0));
+            })
+                .width(Pixels(300.0))
+                .alignment(Alignment::TopCenter);
+        });
+    })
+}
+
+/// A labeled slider for one of the global settings.
+fn setting<P, FMap>(cx: &mut Context, label: &'static str, params_to_param: FMap)
+where
+    P: Param + 'static,
+    FMap: Fn(&Arc<VelocityMapperParams>) -> &P + 'static + Copy,
+{
+    HStack::new(cx, |cx| {
+        Element::new(cx).width(Pixels(10.0));
+
+        Label::new(cx, label)
+            .font_size(12.0)
+            .width(Stretch(1.0));
+
+        ParamSlider::new(cx, Data::params, params_to_param)
+            .font_size(10.0)
+            .width(Pixels(130.0))
+            .height(Pixels(25.0));
+
+        Element::new(cx).width(Pixels(10.0));
+    })
+        .height(Pixels(30.0))
+        .alignment(Alignment::Left);
+}
 
-                //Binding::new(cx, Data::how_many, |cx, how_many| {
-                    for x in 0..4 { //how_many.get(cx) {
-                        range_selector(cx, Data::params, move |params| &params.ranges[x], &(x+1).to_string());
+/// The input pitches of the pads in one crosstalk group, in two rows.
+fn crosstalk_group(cx: &mut Context, group: usize) {
+    HStack::new(cx, |cx| {
+        Element::new(cx).width(Pixels(10.0));
 
-                        Element::new(cx).height(Pixels(10.0));
-                    }
-                //});
-            //});
+        Label::new(cx, format!("Crosstalk Group {}:", group + 1))
+            .font_size(12.0)
+            .width(Stretch(1.0));
+    })
+        .height(Pixels(25.0))
+        .alignment(Alignment::Left);
+
+    for row in 0..2 {
+        HStack::new(cx, |cx| {
+            for pad in (row * MAX_GROUP_PADS / 2)..((row + 1) * MAX_GROUP_PADS / 2) {
+                ParamSlider::new(cx, Data::params, move |params| &params.crosstalk_groups[group].pads[pad].pitch)
+                    .font_size(10.0)
+                    .width(Pixels(60.0))
+                    .height(Pixels(25.0));
+            }
         })
-            .alignment(Alignment::TopCenter);
+            .height(Pixels(30.0))
+            .gap(Pixels(5.0))
+            .alignment(Alignment::Center);
+    }
+}
+
+/// A combine rule: its on/off button, the two input pitches and the pitch they are combined into.
+fn combine_rule(cx: &mut Context, rule: usize) {
+    HStack::new(cx, |cx| {
+        ParamButton::new(cx, Data::params, move |params| &params.combine_rules[rule].active)
+            .with_label(format!("Rule {}", rule + 1))
+            .font_size(10.0)
+            .width(Pixels(55.0))
+            .height(Pixels(25.0));
+
+        ParamSlider::new(cx, Data::params, move |params| &params.combine_rules[rule].pitch_a)
+            .font_size(10.0)
+            .width(Pixels(50.0))
+            .height(Pixels(25.0));
+
+        Label::new(cx, "+")
+            .font_size(12.0);
+
+        ParamSlider::new(cx, Data::params, move |params| &params.combine_rules[rule].pitch_b)
+            .font_size(10.0)
+            .width(Pixels(50.0))
+            .height(Pixels(25.0));
+
+        Svg::new(cx, include_str!("../assets/arrow_right.svg"))
+            .height(Pixels(20.0))
+            .width(Pixels(20.0));
+
+        ParamSlider::new(cx, Data::params, move |params| &params.combine_rules[rule].output)
+            .font_size(10.0)
+            .width(Pixels(50.0))
+            .height(Pixels(25.0));
     })
+        .height(Pixels(30.0))
+        .gap(Pixels(5.0))
+        .alignment(Alignment::Center);
+}
+
+/// Describes where a range stands when several ranges match the same note.
+fn priority_label(params: &VelocityMapperParams, idx: usize) -> String {
+    match params.overlap.value() {
+        OverlapMode::First => format!("priority {}", idx + 1),
+        OverlapMode::Last => format!("priority {}", params.how_many.value() as usize - idx),
+        OverlapMode::All => String::from("layered"),
+        OverlapMode::Narrowest => String::from("narrowest wins"),
+        OverlapMode::Random => String::from("random pick"),
+    }
 }
 
-fn range_selector<L, Params, FMap>(cx: &mut Context, params: L, params_to_param: FMap, label_suffix: &str)
+fn range_selector<L, Params, FMap, P>(
+    cx: &mut Context,
+    params: L,
+    params_to_param: FMap,
+    label_suffix: &str,
+    priority: P,
+)
 where
     L: Lens<Target = Params> + Clone,
     Params: 'static,
     FMap: Fn(&Params) -> &RangeParams + 'static + Copy,
+    P: Lens<Target = String>,
 {
     HStack::new(cx, move |cx| {
         Element::new(cx).width(Stretch(0.1));
@@ -80,9 +316,24 @@ where
 
             Element::new(cx).height(Pixels(5.0));
 
-            Label::new(cx, format!("Mapping {}:", label_suffix))
-                .font_weight(Bold)
-                .font_size(15.0);
+            HStack::new(cx, |cx| {
+                Label::new(cx, format!("Mapping {}:", label_suffix))
+                    .font_weight(Bold)
+                    .font_size(15.0);
+
+                // where this range stands when several ranges match
+                Label::new(cx, priority)
+                    .font_size(10.0);
+
+                // Remap or drop
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).action)
+                    .font_size(10.0)
+                    .width(Pixels(60.0))
+                    .height(Pixels(20.0));
+            })
+                .height(Pixels(20.0))
+                .gap(Pixels(10.0))
+                .alignment(Alignment::Center);
 
             Element::new(cx).height(Pixels(5.0));
 
@@ -169,14 +420,312 @@ where
             })
                 .height(Pixels(90.0))
                 .alignment(Alignment::Center);
+
+            // Only match while the pedal CC is in this window
+            range_row(cx, "Pedal:", |cx| {
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).pedal_min
+                    },
+                );
+
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).pedal_max
+                    },
+                );
+            });
+
+            // Only match fast repetitions of the same pitch, or only slower ones
+            range_row(cx, "Roll:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).roll)
+                    .font_size(10.0)
+                    .width(Pixels(70.0))
+                    .height(Pixels(25.0));
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).roll_ms)
+                    .font_size(10.0)
+                    .width(Pixels(60.0))
+                    .height(Pixels(25.0));
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).roll_hysteresis)
+                    .font_size(10.0)
+                    .width(Pixels(50.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Match the NoteOn or the release velocity
+            range_row(cx, "Trigger:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).trigger)
+                    .font_size(10.0)
+                    .width(Pixels(90.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Held time for release triggers
+            range_row(cx, "Held:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).held_min)
+                    .font_size(10.0)
+                    .width(Pixels(80.0))
+                    .height(Pixels(25.0));
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).held_max)
+                    .font_size(10.0)
+                    .width(Pixels(80.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Only match these keys and this channel
+            range_row(cx, "Keys:", |cx| {
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).key_min
+                    },
+                );
+
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).key_max
+                    },
+                );
+
+                Label::new(cx, "Ch:")
+                    .font_size(10.0);
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).input_channel)
+                    .font_size(10.0)
+                    .width(Pixels(50.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Absolute pitch or relative transposition
+            range_row(cx, "Mode:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).pitch_mode)
+                    .font_size(10.0)
+                    .width(Pixels(90.0))
+                    .height(Pixels(25.0));
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).transpose)
+                    .font_size(10.0)
+                    .width(Pixels(70.0))
+                    .height(Pixels(25.0));
+
+                ParamButton::new(cx, params, move |params| &params_to_param(params).quantize)
+                    .font_size(10.0)
+                    .height(Pixels(25.0));
+            });
+
+            // Additional pitches that are sent out together with the main pitch, or cycled through
+            range_row(cx, "Order:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).pitch_order)
+                    .font_size(10.0)
+                    .width(Pixels(120.0))
+                    .height(Pixels(25.0));
+
+                // weight of the main pitch
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).weight)
+                    .font_size(10.0)
+                    .width(Pixels(50.0))
+                    .height(Pixels(25.0));
+            });
+
+            range_row(cx, "Layers:", |cx| {
+                for layer in 0..MAX_LAYERS {
+                    VStack::new(cx, |cx| {
+                        ParamButton::new(cx, params, move |params| &params_to_param(params).layers[layer].active)
+                            .font_size(10.0)
+                            .width(Pixels(50.0))
+                            .height(Pixels(18.0));
+
+                        DropDownParam::new(
+                            cx,
+                            params,
+                            move |params| {
+                                &params_to_param(params).layers[layer].pitch
+                            },
+                        );
+                    })
+                        .width(Pixels(50.0))
+                        .gap(Pixels(2.0));
+                }
+            });
+
+            range_row(cx, "Weights:", |cx| {
+                for layer in 0..MAX_LAYERS {
+                    ParamSlider::new(cx, params, move |params| &params_to_param(params).layers[layer].weight)
+                        .font_size(10.0)
+                        .width(Pixels(50.0))
+                        .height(Pixels(25.0));
+                }
+            });
+
+            // Randomly skip notes
+            range_row(cx, "Chance:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).probability)
+                    .font_size(10.0)
+                    .width(Pixels(120.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Output channel and extra channels the notes are layered on
+            range_row(cx, "Channels:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).output_channel)
+                    .font_size(10.0)
+                    .width(Pixels(50.0))
+                    .height(Pixels(25.0));
+
+                for extra in 0..MAX_EXTRA_CHANNELS {
+                    ParamSlider::new(cx, params, move |params| &params_to_param(params).extra_channels[extra].channel)
+                        .font_size(10.0)
+                        .width(Pixels(40.0))
+                        .height(Pixels(25.0));
+                }
+            });
+
+            // Output velocity
+            range_row(cx, "Velocity:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).velocity_mode)
+                    .font_size(10.0)
+                    .width(Pixels(90.0))
+                    .height(Pixels(25.0));
+
+                Label::new(cx, "Fixed:")
+                    .font_size(10.0);
+
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).fixed_velocity
+                    },
+                );
+            });
+
+            range_row(cx, "Rescale:", |cx| {
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).out_velocity_min
+                    },
+                );
+
+                DropDownParam::new(
+                    cx,
+                    params,
+                    move |params| {
+                        &params_to_param(params).out_velocity_max
+                    },
+                );
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).velocity_curve)
+                    .font_size(10.0)
+                    .width(Pixels(70.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Note length
+            range_row(cx, "Gate:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).gate_mode)
+                    .font_size(10.0)
+                    .width(Pixels(90.0))
+                    .height(Pixels(25.0));
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).gate_unit)
+                    .font_size(10.0)
+                    .width(Pixels(70.0))
+                    .height(Pixels(25.0));
+            });
+
+            range_row(cx, "Length:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).gate_ms)
+                    .font_size(10.0)
+                    .width(Pixels(90.0))
+                    .height(Pixels(25.0));
+
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).gate_note)
+                    .font_size(10.0)
+                    .width(Pixels(70.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Cut off other ranges in the same group
+            range_row(cx, "Choke:", |cx| {
+                ParamSlider::new(cx, params, move |params| &params_to_param(params).choke_group)
+                    .font_size(10.0)
+                    .width(Pixels(70.0))
+                    .height(Pixels(25.0));
+            });
+
+            // Keyswitches, CCs and program changes sent before the notes
+            range_row(cx, "Messages:", |cx| {
+                ParamButton::new(cx, params, move |params| &params_to_param(params).only_on_change)
+                    .font_size(10.0)
+                    .height(Pixels(25.0));
+            });
+
+            for message in 0..MAX_MESSAGES {
+                range_row(cx, MESSAGE_LABELS[message], |cx| {
+                    ParamSlider::new(cx, params, move |params| &params_to_param(params).messages[message].kind)
+                        .font_size(10.0)
+                        .width(Pixels(90.0))
+                        .height(Pixels(25.0));
+
+                    DropDownParam::new(
+                        cx,
+                        params,
+                        move |params| {
+                            &params_to_param(params).messages[message].number
+                        },
+                    );
+
+                    DropDownParam::new(
+                        cx,
+                        params,
+                        move |params| {
+                            &params_to_param(params).messages[message].value
+                        },
+                    );
+                });
+            }
+
+            Element::new(cx).height(Pixels(5.0));
         })
             .border_color(Color::black())
             .border_width(Pixels(1.0))
             .alignment(Alignment::Center)
             .width(Stretch(2.0))
-            .height(Pixels(120.0));
+            .height(Auto);
 
         Element::new(cx).width(Stretch(0.1));
     })
-        .height(Pixels(120.0));
+        .height(Auto);
+}
+
+const MESSAGE_LABELS: [&str; MAX_MESSAGES] = ["1:", "2:", "3:", "4:"];
+
+/// A labeled row of additional settings inside a [`range_selector`].
+fn range_row(cx: &mut Context, label: &'static str, content: impl FnOnce(&mut Context)) {
+    HStack::new(cx, |cx| {
+        Element::new(cx).width(Pixels(10.0));
+
+        Label::new(cx, label)
+            .font_size(12.0)
+            .width(Pixels(60.0));
+
+        content(cx);
+
+        Element::new(cx).width(Pixels(10.0));
+    })
+        .height(Pixels(50.0))
+        .gap(Pixels(3.0))
+        .alignment(Alignment::Left);
 }
\ No newline at end of file
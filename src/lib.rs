@@ -1,20 +1,77 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use nih_plug::prelude::*;
 use vizia_plug::vizia::vg::luma_color_filter::new;
-use crate::params::{RangeParams, VelocityMapperParams};
+use smallvec::{smallvec, SmallVec};
+use crate::delay_line::{DelayLine, DelayedEvent};
+use crate::note_stack::{push_output, HeldNote, NoteStack, OutputNote, Outputs};
+use crate::params::{ChokeMode, GateMode, GateUnit, MessageKind, OverlapMode, PitchMode, PitchOrder, RangeAction, RangeParams, RetriggerMode, RollCondition, ScaleType, Trigger, UnmatchedMode, VelocityCcMode, VelocityCcSource, VelocityMapperParams, VelocityMode, MAX_LAYERS, MAX_RANGES};
+use crate::rng::Rng;
+use crate::round_robin::RoundRobin;
+use crate::scheduler::{ScheduledNoteOff, Scheduler};
 
 mod editor;
 mod params;
 mod gui;
+mod delay_line;
+mod note_stack;
+mod rng;
+mod round_robin;
+mod scale;
+mod scheduler;
+mod timed_queue;
 
 const MAX_CHANNELS: usize = 16;
-const MAX_PITCHES: usize = 128;
-const MAX_VOICES: usize = 4; // max overlapping notes per pitch
+
+/// How long notes triggered by a release are held, if their range has no gate length. A NoteOff at
+/// the same sample as its NoteOn is ignored by many samplers.
+const TRIGGERED_NOTE_MS: f32 = 50.0;
+
+/// Where the plugin sends its output events. This is the process context, or a list of events in
+/// tests.
+trait EventSink {
+    fn send_event(&mut self, event: NoteEvent<()>);
+}
+
+impl<C: ProcessContext<VelocityMapper>> EventSink for C {
+    fn send_event(&mut self, event: NoteEvent<()>) {
+        ProcessContext::send_event(self, event)
+    }
+}
 
 struct VelocityMapper {
     params: Arc<VelocityMapperParams>,
-    // This stores NoteOns to match with NoteOffs by channel, by pitch, with Option<new_pitch>
-    note_stack: Vec<Vec<Vec<Option<u8>>>>,
+    // This stores NoteOns and the pitch they were mapped to, to match them with NoteOffs
+    note_stack: NoteStack,
+    // Set by the editor's panic button, handled at the start of the next process call
+    panic: Arc<AtomicBool>,
+    // Release all held notes at the start of the next process call
+    flush_pending: bool,
+    was_playing: bool,
+    rng: Rng,
+    // Where each range is in its list of output pitches
+    round_robin: [RoundRobin; MAX_RANGES],
+    // The range that last sent its messages on each channel
+    last_articulation: [Option<usize>; MAX_CHANNELS],
+    sample_rate: f32,
+    // The number of samples processed so far, the timestamp of the current buffer's first sample
+    sample_pos: u64,
+    tempo: f64,
+    // NoteOffs for gated notes that are sent later
+    scheduler: Scheduler,
+    // The last value of the pedal CC on each channel
+    pedal_values: [f32; MAX_CHANNELS],
+    // The last NoteOn that got through on each channel and pitch, to find double triggers and
+    // crosstalk
+    last_note_ons: [[Option<LastNoteOn>; 128]; MAX_CHANNELS],
+    // Incoming events, held back so a NoteOn can be combined with one that arrives shortly after
+    delay_line: DelayLine,
+    // The latency reported to the host, in samples
+    latency: u32,
+    // For each range and channel, one bit per pitch that is currently played in a roll
+    rolling: [[u128; MAX_CHANNELS]; MAX_RANGES],
+    // When each pitch on each channel last reached the ranges as a NoteOn, to find rolls
+    last_hits: [[Option<u64>; 128]; MAX_CHANNELS],
 }
 
 impl Default for VelocityMapper {
@@ -22,52 +79,1026 @@ impl Default for VelocityMapper {
         let default_params = Arc::new(VelocityMapperParams::default());
         Self {
             params: default_params.clone(),
-            note_stack: vec![vec![vec![None; MAX_VOICES]; MAX_PITCHES]; MAX_CHANNELS],
+            note_stack: NoteStack::default(),
+            panic: Arc::new(AtomicBool::new(false)),
+            flush_pending: false,
+            was_playing: false,
+            rng: Rng::new(0),
+            round_robin: [RoundRobin::default(); MAX_RANGES],
+            last_articulation: [None; MAX_CHANNELS],
+            sample_rate: 44100.0,
+            sample_pos: 0,
+            tempo: 120.0,
+            scheduler: Scheduler::default(),
+            pedal_values: [0.0; MAX_CHANNELS],
+            last_note_ons: [[None; 128]; MAX_CHANNELS],
+            delay_line: DelayLine::default(),
+            latency: 0,
+            rolling: [[0; MAX_CHANNELS]; MAX_RANGES],
+            last_hits: [[None; 128]; MAX_CHANNELS],
         }
     }
 }
 
 impl VelocityMapper {
-    fn get_remapped_pitch(&mut self, velocity: f32) -> Option<u8> {
-        for range_params in &self.params.ranges {
-            if let Some(pitch) =  matches_range(velocity, range_params) {
-                return Some(pitch);
+    fn hit(&self, trigger: Trigger, channel: u8, note: u8, velocity: f32) -> Hit {
+        Hit {
+            trigger,
+            channel,
+            note,
+            velocity,
+            held_ms: 0.0,
+            pedal: self.pedal_values[channel as usize],
+        }
+    }
+
+    /// The indices of all active ranges that match a note, in slot order.
+    fn matching_ranges(&self, hit: Hit) -> impl Iterator<Item = usize> + '_ {
+        // only the first `how_many` ranges are active, the rest are ignored
+        let how_many = self.params.how_many.value() as usize;
+
+        self.params.ranges
+            .iter()
+            .take(how_many)
+            .enumerate()
+            .filter(move |(idx, range_params)| {
+                matches_range(&hit, range_params, self.is_rolling(*idx, hit.channel, hit.note))
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    /// The notes a hit should be sent out as, or `None` if no range matches it. Dropped notes are
+    /// sent out as no notes at all.
+    fn get_outputs(&mut self, hit: Hit) -> Option<Outputs> {
+        let Hit { channel, note, velocity, .. } = hit;
+
+        // which of the matching ranges are used
+        let mut chosen: SmallVec<[usize; MAX_RANGES]> = SmallVec::new();
+        match self.params.overlap.value() {
+            OverlapMode::First => chosen.extend(self.matching_ranges(hit).next()),
+            OverlapMode::Last => chosen.extend(self.matching_ranges(hit).last()),
+            OverlapMode::All => chosen.extend(self.matching_ranges(hit)),
+            OverlapMode::Narrowest => chosen.extend(
+                self.matching_ranges(hit)
+                    .min_by_key(|&idx| range_width(&self.params.ranges[idx]))
+            ),
+            OverlapMode::Random => {
+                let count = self.matching_ranges(hit).count();
+                if count > 0 {
+                    let pick = self.rng.below(count);
+                    chosen.extend(self.matching_ranges(hit).nth(pick));
+                }
+            },
+        }
+
+        if chosen.is_empty() {
+            return None;
+        }
+
+        let scale_mask = self.scale_mask();
+        let scale_root = self.params.scale_root.value() as u8;
+
+        let mut outputs = Outputs::new();
+        if chosen.iter().any(|&idx| self.params.ranges[idx].action.value() == RangeAction::Drop) {
+            return Some(outputs);
+        }
+
+        for idx in chosen {
+            let range_params = &self.params.ranges[idx];
+
+            // randomly skip this range
+            let probability = range_params.probability.value();
+            if probability < 1.0 && self.rng.next_f32() >= probability {
+                continue;
+            }
+
+            let velocity = output_velocity(velocity, range_params);
+
+            // either all pitches at once, or the next one in the cycle
+            let mut pitches: SmallVec<[u8; MAX_LAYERS + 1]> = output_pitches(range_params, note).collect();
+            let order = range_params.pitch_order.value();
+            if order != PitchOrder::Layer {
+                let weights: SmallVec<[f32; MAX_LAYERS + 1]> = output_weights(range_params).collect();
+                let pick = self.round_robin[idx].pick(order, &weights, &mut self.rng);
+                pitches = smallvec![pitches[pick]];
+            }
+
+            // snap the pitches to the scale
+            if let Some(mask) = scale_mask.filter(|_| range_params.quantize.value()) {
+                for pitch in &mut pitches {
+                    *pitch = scale::quantize(*pitch, scale_root, mask);
+                }
+            }
+
+            for out_channel in output_channels(range_params, channel) {
+                for &pitch in &pitches {
+                    push_output(&mut outputs, OutputNote {
+                        channel: out_channel,
+                        note: pitch,
+                        velocity,
+                        range: Some(idx),
+                    });
+                }
+            }
+        }
+        Some(outputs)
+    }
+
+    fn handle_event(&mut self, event: NoteEvent<()>, context: &mut impl EventSink) {
+        match event {
+            NoteEvent::NoteOn {
+                timing,
+                voice_id,
+                channel,
+                note,
+                velocity,
+            } => {
+                let started_at = self.sample_pos + timing as u64;
+
+                // drop crosstalk, its NoteOff is swallowed as well
+                if self.is_crosstalk(channel, note, velocity, started_at) {
+                    self.suppress_note_on(context, timing, channel, note, voice_id, velocity);
+                    return;
+                }
+
+                // double triggers are folded into the note that is kept, which is only released by
+                // the last of their NoteOffs
+                let mut folded_note_offs = 0;
+                match self.double_trigger(channel, note, velocity, started_at) {
+                    Some(DoubleTrigger::Drop) => {
+                        // unless that note was already released
+                        if !self.note_stack.fold_note_offs(channel, note, 1) {
+                            self.suppress_note_on(context, timing, channel, note, voice_id, velocity);
+                        }
+                        return;
+                    },
+                    Some(DoubleTrigger::ReplaceEarlier) => {
+                        if let Some(earlier) = self.note_stack.pop_newest(channel, note) {
+                            for output in &earlier.outputs {
+                                self.scheduler.cancel(output.channel, output.note);
+                                send_note_off(context, timing, earlier.voice_id, output, 0.0);
+                            }
+                            folded_note_offs = earlier.folded_note_offs + 1;
+                        }
+                    },
+                    None => (),
+                }
+
+                // a hit on the other pad of a combine rule turns both into a single note
+                let (input_note, velocity) = self.combine(context, timing, channel, note, velocity)
+                    .unwrap_or((note, velocity));
+
+                let hit = self.hit(Trigger::NoteOn, channel, input_note, velocity);
+                self.update_rolls(channel, input_note, started_at);
+
+                // remap the NoteOn, or pass it through or block it if no range matches
+                let outputs = self.get_outputs(hit);
+                // dropped by its range or skipped by chance, then its NoteOff doesn't trigger
+                // anything either
+                let dropped = outputs.as_ref().is_some_and(|outputs| outputs.is_empty());
+                let outputs = match outputs {
+                    Some(outputs) => {
+                        self.choke(context, timing, &outputs);
+                        // the controllers and keyswitches have to arrive before the notes
+                        self.send_velocity_controllers(context, timing, velocity, &outputs);
+                        self.send_pre_note_messages(context, timing, &outputs);
+                        outputs
+                    },
+                    None => match self.params.unmatched.value() {
+                        UnmatchedMode::Pass => smallvec![OutputNote { channel, note: input_note, velocity, range: None }],
+                        UnmatchedMode::Block => Outputs::new(),
+                    },
+                };
+
+                self.send_note_ons(context, timing, voice_id, &outputs);
+
+                // ranges with a fixed or maximum gate release their notes on their own
+                for output in &outputs {
+                    if let Some((GateMode::Fixed | GateMode::Maximum, length)) = self.gate(output) {
+                        self.schedule_note_off(context, timing, voice_id, output, started_at.saturating_add(length));
+                    }
+                }
+
+                // remember this NoteOn and which notes it was mapped to, even if it was blocked, so
+                // its NoteOff is blocked as well
+                let evicted = if dropped {
+                    self.note_stack.push_suppressed(channel, note, voice_id, velocity, started_at)
+                } else {
+                    self.note_stack.push(channel, note, voice_id, velocity, started_at, outputs)
+                };
+                if folded_note_offs > 0 {
+                    self.note_stack.fold_note_offs(channel, note, folded_note_offs);
+                }
+
+                // we ran out of space, release the oldest note so it doesn't get stuck
+                if let Some(evicted) = evicted {
+                    send_note_offs(context, timing, evicted.voice_id, &evicted.outputs, 0.0);
+                }
+            },
+            NoteEvent::NoteOff {
+                timing,
+                voice_id,
+                channel,
+                note,
+                velocity,
+            } => {
+                let matching = self.params.note_off_matching.value();
+
+                // the NoteOff of a double trigger, the note waits for the last one
+                if self.note_stack.swallow_folded_note_off(channel, note, voice_id, matching) {
+                    return;
+                }
+
+                // release all notes the matching NoteOn was mapped to
+                let held = self.note_stack.pop(channel, note, voice_id, matching);
+                match &held {
+                    Some(held) => self.release_held_note(context, timing, voice_id, held, velocity),
+                    None => context.send_event(event),
+                }
+
+                if held.as_ref().is_some_and(|held| held.suppressed) {
+                    return;
+                }
+
+                // ranges can also trigger short notes on the release velocity
+                let hit = self.hit(Trigger::NoteOff, channel, note, velocity);
+                self.send_triggered_notes(context, timing, hit);
+
+                // or on how long the note was held and how hard it was played
+                if let Some(held) = held {
+                    let held_samples = (self.sample_pos + timing as u64).saturating_sub(held.started_at);
+                    let held_ms = held_samples as f32 / self.sample_rate * 1000.0;
+                    let hit = Hit { held_ms, ..self.hit(Trigger::Release, channel, note, held.velocity) };
+                    self.send_triggered_notes(context, timing, hit);
+                }
+            },
+            // a choked note is gone, so a later NoteOff can't be paired with it anymore
+            NoteEvent::Choke { channel, note, .. } => {
+                match self.note_stack.pop(channel, note, event.voice_id(), self.params.note_off_matching.value()) {
+                    Some(held) => {
+                        for output in &held.outputs {
+                            self.scheduler.remove_where(|note_off| {
+                                note_off.voice_id == held.voice_id
+                                    && note_off.channel == output.channel
+                                    && note_off.note == output.note
+                                    && note_off.range == output.range
+                            });
+                            context.send_event(retarget(event, output))
+                        }
+                    },
+                    None => context.send_event(event),
+                }
+            },
+            // polyphonic events follow the notes their NoteOn was remapped to
+            NoteEvent::VoiceTerminated { channel, note, .. }
+            | NoteEvent::PolyPressure { channel, note, .. }
+            | NoteEvent::PolyVolume { channel, note, .. }
+            | NoteEvent::PolyPan { channel, note, .. }
+            | NoteEvent::PolyTuning { channel, note, .. }
+            | NoteEvent::PolyVibrato { channel, note, .. }
+            | NoteEvent::PolyExpression { channel, note, .. }
+            | NoteEvent::PolyBrightness { channel, note, .. } => {
+                match self.note_stack.peek(channel, note, event.voice_id(), self.params.note_off_matching.value()) {
+                    Some(held) => {
+                        for output in &held.outputs {
+                            context.send_event(retarget(event, output))
+                        }
+                    },
+                    None => context.send_event(event),
+                }
+            },
+            // remember the pedal position, ranges can match on it
+            NoteEvent::MidiCC { channel, cc, value, .. } => {
+                if cc as i32 == self.params.pedal_cc.value() {
+                    self.pedal_values[channel as usize] = value;
+                }
+                context.send_event(event)
+            },
+            // everything else (pitch bend, program changes, ...) is passed through untouched
+            _ => context.send_event(event),
+        }
+    }
+
+    /// Whether a range currently sees this channel and pitch as played in a roll.
+    fn is_rolling(&self, range: usize, channel: u8, note: u8) -> bool {
+        self.rolling[range][channel as usize] & (1u128 << note) != 0
+    }
+
+    /// Start or end the rolls of all ranges on this channel and pitch, depending on the time since
+    /// its last NoteOn. The hysteresis keeps intervals right at a range's roll interval from
+    /// flipping back and forth.
+    fn update_rolls(&mut self, channel: u8, note: u8, now: u64) {
+        let last_hit = self.last_hits[channel as usize][note as usize].replace(now);
+        let interval_ms = match last_hit {
+            Some(last_hit) => now.saturating_sub(last_hit) as f32 / self.sample_rate * 1000.0,
+            None => f32::INFINITY,
+        };
+
+        let bit = 1u128 << note;
+        for (rolling, range_params) in self.rolling.iter_mut().zip(&self.params.ranges) {
+            let rolling = &mut rolling[channel as usize];
+            let threshold = range_params.roll_ms.value();
+            let hysteresis = range_params.roll_hysteresis.value();
+
+            let was_rolling = *rolling & bit != 0;
+            let is_rolling = if was_rolling {
+                interval_ms < threshold * (1.0 + hysteresis)
+            } else {
+                interval_ms < threshold * (1.0 - hysteresis)
+            };
+
+            if is_rolling {
+                *rolling |= bit;
+            } else {
+                *rolling &= !bit;
             }
         }
+    }
+
+    /// If a NoteOn and a NoteOn on the other pitch of a combine rule arrive within the combine
+    /// window, the later one is taken out of the delay line and its NoteOff is swallowed. Returns
+    /// the combined pitch and velocity the earlier NoteOn should be played with.
+    fn combine(
+        &mut self,
+        context: &mut impl EventSink,
+        timing: u32,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    ) -> Option<(u8, f32)> {
+        let window = self.combine_window();
+        if window == 0 {
+            return None;
+        }
+        let now = self.sample_pos + timing as u64;
+
+        for rule in self.params.combine_rules.iter().filter(|rule| rule.active.value()) {
+            let other = if rule.pitch_a.value() == note as i32 {
+                rule.pitch_b.value()
+            } else if rule.pitch_b.value() == note as i32 {
+                rule.pitch_a.value()
+            } else {
+                continue;
+            };
+
+            let Some(DelayedEvent {
+                due,
+                event: NoteEvent::NoteOn { voice_id, velocity: other_velocity, .. },
+            }) = self.delay_line.take_note_on(channel, other as u8, now + window) else { continue };
+
+            let evicted = self.note_stack.push_suppressed(channel, other as u8, voice_id, other_velocity, due);
+            if let Some(evicted) = evicted {
+                send_note_offs(context, timing, evicted.voice_id, &evicted.outputs, 0.0);
+            }
+
+            return Some((rule.output.value() as u8, velocity.max(other_velocity)));
+        }
         None
     }
 
-    fn next_free_index(&self, channel: u8, old_pitch: u8) -> Option<usize> {
-        self.note_stack[channel as usize][old_pitch as usize]
+    /// The combine window in samples, which is also the plugin's latency.
+    fn combine_window(&self) -> u64 {
+        (self.params.combine_ms.value() / 1000.0 * self.sample_rate) as u64
+    }
+
+    /// Handle all delayed events that are due before the `until` sample.
+    fn handle_delayed_events(&mut self, context: &mut impl EventSink, until: u64) {
+        while let Some(DelayedEvent { due, event }) = self.delay_line.pop_due(until) {
+            self.send_due_note_offs(context, due + 1);
+            self.handle_event(with_timing(event, due.saturating_sub(self.sample_pos) as u32), context);
+        }
+    }
+
+    /// Whether a NoteOn was most likely triggered by a much louder hit on another pad of one of its
+    /// crosstalk groups, shortly before.
+    fn is_crosstalk(&self, channel: u8, note: u8, velocity: f32, now: u64) -> bool {
+        let window = (self.params.crosstalk_ms.value() / 1000.0 * self.sample_rate) as u64;
+        if window == 0 {
+            return false;
+        }
+        let ratio = self.params.crosstalk_ratio.value();
+        let last_note_ons = &self.last_note_ons[channel as usize];
+
+        self.params.crosstalk_groups
             .iter()
-            .position(|note| note.is_none())
+            .filter(|group| group.pads.iter().any(|pad| pad.pitch.value() == note as i32))
+            .flat_map(|group| group.pads.iter().map(|pad| pad.pitch.value()))
+            .filter(|&pad| pad >= 0 && pad != note as i32)
+            .filter_map(|pad| last_note_ons[pad as usize])
+            .any(|last| now.saturating_sub(last.at) < window && velocity < last.velocity * ratio)
+    }
+
+    /// Remember a NoteOn that was filtered out, so its NoteOff is swallowed as well.
+    fn suppress_note_on(
+        &mut self,
+        context: &mut impl EventSink,
+        timing: u32,
+        channel: u8,
+        note: u8,
+        voice_id: Option<i32>,
+        velocity: f32,
+    ) {
+        let started_at = self.sample_pos + timing as u64;
+        let evicted = self.note_stack.push_suppressed(channel, note, voice_id, velocity, started_at);
+        if let Some(evicted) = evicted {
+            send_note_offs(context, timing, evicted.voice_id, &evicted.outputs, 0.0);
+        }
+    }
+
+    /// What to do with a NoteOn that comes too soon after the last one of the same pitch, `None` if
+    /// it doesn't.
+    fn double_trigger(&mut self, channel: u8, note: u8, velocity: f32, now: u64) -> Option<DoubleTrigger> {
+        let window = (self.params.retrigger_ms.value() / 1000.0 * self.sample_rate) as u64;
+        let last_note_on = &mut self.last_note_ons[channel as usize][note as usize];
+        let this_note_on = LastNoteOn { at: now, velocity };
+
+        let last = match *last_note_on {
+            Some(last) if window > 0 && now.saturating_sub(last.at) < window => last,
+            _ => {
+                *last_note_on = Some(this_note_on);
+                return None;
+            },
+        };
+
+        if self.params.retrigger_mode.value() == RetriggerMode::DropSecond || velocity <= last.velocity {
+            return Some(DoubleTrigger::Drop);
+        }
+        *last_note_on = Some(this_note_on);
+        Some(DoubleTrigger::ReplaceEarlier)
+    }
+
+    /// Send the short notes of all ranges that match a release. They are released after
+    /// [`TRIGGERED_NOTE_MS`], or after their range's gate length.
+    fn send_triggered_notes(&mut self, context: &mut impl EventSink, timing: u32, hit: Hit) {
+        if let Some(outputs) = self.get_outputs(hit) {
+            self.choke(context, timing, &outputs);
+            self.send_velocity_controllers(context, timing, hit.velocity, &outputs);
+            self.send_pre_note_messages(context, timing, &outputs);
+            self.send_note_ons(context, timing, None, &outputs);
+
+            let now = self.sample_pos + timing as u64;
+            let default_length = ((TRIGGERED_NOTE_MS / 1000.0 * self.sample_rate) as u64).max(1);
+            for output in &outputs {
+                let length = match self.gate(output) {
+                    Some((GateMode::Fixed | GateMode::Minimum, length)) => length,
+                    Some((GateMode::Maximum, length)) => length.min(default_length),
+                    _ => default_length,
+                };
+                self.schedule_note_off(context, timing, None, output, now.saturating_add(length));
+            }
+        }
+    }
+
+    /// Send NoteOns for the outputs. A NoteOff that is still scheduled for the same note is sent
+    /// first, so it can't cut the new note short later on.
+    fn send_note_ons(
+        &mut self,
+        context: &mut impl EventSink,
+        timing: u32,
+        voice_id: Option<i32>,
+        outputs: &Outputs,
+    ) {
+        for output in outputs {
+            if let Some(note_off) = self.scheduler.cancel(output.channel, output.note) {
+                self.forget_scheduled_output(&note_off);
+                send_note_off(context, timing, None, output, 0.0);
+            }
+
+            context.send_event(NoteEvent::NoteOn {
+                timing,
+                voice_id,
+                channel: output.channel,
+                note: output.note,
+                velocity: output.velocity,
+            })
+        }
+    }
+
+    /// Release the outputs of a note whose NoteOff just arrived, according to their gate modes.
+    fn release_held_note(
+        &mut self,
+        context: &mut impl EventSink,
+        timing: u32,
+        voice_id: Option<i32>,
+        held: &HeldNote,
+        velocity: f32,
+    ) {
+        let now = self.sample_pos + timing as u64;
+
+        for output in &held.outputs {
+            match self.gate(output) {
+                // the scheduled NoteOff releases this note
+                Some((GateMode::Fixed, _)) => (),
+                // unless the maximum length has already released it
+                Some((GateMode::Maximum, _)) => {
+                    if self.scheduler.cancel(output.channel, output.note).is_some() {
+                        send_note_off(context, timing, voice_id, output, velocity);
+                    }
+                },
+                // hold it a bit longer
+                Some((GateMode::Minimum, length)) if now < held.started_at.saturating_add(length) => {
+                    self.schedule_note_off(context, timing, voice_id, output, held.started_at.saturating_add(length));
+                },
+                _ => send_note_off(context, timing, voice_id, output, velocity),
+            }
+        }
+    }
+
+    /// The gate mode and gate length in samples of the range that produced an output, `None` if
+    /// the output just follows the incoming NoteOff.
+    fn gate(&self, output: &OutputNote) -> Option<(GateMode, u64)> {
+        let range_params = &self.params.ranges[output.range?];
+        let mode = range_params.gate_mode.value();
+        if mode == GateMode::Off {
+            return None;
+        }
+
+        let seconds = match range_params.gate_unit.value() {
+            GateUnit::Milliseconds => range_params.gate_ms.value() as f64 / 1000.0,
+            GateUnit::NoteValue => range_params.gate_note.value().beats() * 60.0 / self.tempo,
+        };
+        let length = (seconds * self.sample_rate as f64).round() as u64;
+
+        Some((mode, length.max(1)))
     }
 
-    fn push_note_on(&mut self, channel: u8, old_pitch: u8, new_pitch: u8) {
-        let idx = self.next_free_index(channel, old_pitch).unwrap_or(MAX_VOICES - 1);
-        self.note_stack[channel as usize][old_pitch as usize][idx] = Some(new_pitch);
+    /// Send a NoteOff at the `due` sample, or right away if the scheduler is full.
+    fn schedule_note_off(
+        &mut self,
+        context: &mut impl EventSink,
+        timing: u32,
+        voice_id: Option<i32>,
+        output: &OutputNote,
+        due: u64,
+    ) {
+        let note_off = ScheduledNoteOff {
+            due,
+            voice_id,
+            channel: output.channel,
+            note: output.note,
+            range: output.range,
+        };
+        if !self.scheduler.push(note_off) {
+            send_note_off(context, timing, voice_id, output, 0.0);
+        }
     }
 
-    fn pop_note_on(&mut self, channel: u8, old_pitch: u8) -> Option<u8> {
-        let idx = self.next_free_index(channel, old_pitch).unwrap_or(MAX_VOICES);
-        if idx == 0 {
-            None
-        } else {
-            let new_pitch = self.note_stack[channel as usize][old_pitch as usize][idx-1];
-            self.note_stack[channel as usize][old_pitch as usize][idx-1] = None;
-            new_pitch
+    /// Send all scheduled NoteOffs that are due before the `until` sample.
+    fn send_due_note_offs(&mut self, context: &mut impl EventSink, until: u64) {
+        while let Some(note_off) = self.scheduler.pop_due(until) {
+            self.forget_scheduled_output(&note_off);
+            context.send_event(NoteEvent::NoteOff {
+                timing: note_off.due.saturating_sub(self.sample_pos) as u32,
+                voice_id: note_off.voice_id,
+                channel: note_off.channel,
+                note: note_off.note,
+                velocity: 0.0,
+            })
+        }
+    }
+
+    /// Once the scheduled NoteOff of a gated output is sent, the output of the held note it belongs
+    /// to isn't sounding anymore, so it can't be choked or released again.
+    fn forget_scheduled_output(&mut self, note_off: &ScheduledNoteOff) {
+        self.note_stack.remove_outputs(|voice_id, output| {
+            voice_id == note_off.voice_id
+                && output.channel == note_off.channel
+                && output.note == note_off.note
+                && output.range == note_off.range
+        });
+    }
+
+    /// Cut off all notes of other ranges in the choke groups of the ranges that produced the outputs.
+    fn choke(&mut self, context: &mut impl EventSink, timing: u32, outputs: &Outputs) {
+        let mode = self.params.choke_mode.value();
+        // gated notes are both held and scheduled, but every note is only choked once
+        let mut choked_notes = [0u128; MAX_CHANNELS];
+
+        for (idx, output) in outputs.iter().enumerate() {
+            let Some(range) = output.range else { continue };
+            let group = self.params.ranges[range].choke_group.value();
+            if group == 0 || outputs[..idx].iter().any(|other| other.range == output.range) {
+                continue;
+            }
+
+            let params = &self.params;
+            let is_choked = |other: Option<usize>| {
+                other.is_some_and(|other| other != range && params.ranges[other].choke_group.value() == group)
+            };
+
+            // notes that are still held, and notes that are only waiting for their gate to end
+            self.note_stack.remove_outputs(|voice_id, held_output| {
+                let choked = is_choked(held_output.range);
+                if choked {
+                    send_choke(&mut choked_notes, context, timing, voice_id, held_output.channel, held_output.note, mode);
+                }
+                choked
+            });
+            self.scheduler.remove_where(|note_off| {
+                let choked = is_choked(note_off.range);
+                if choked {
+                    send_choke(&mut choked_notes, context, timing, note_off.voice_id, note_off.channel, note_off.note, mode);
+                }
+                choked
+            });
         }
     }
+
+    /// Send the velocity as a CC or channel pressure message, once on every channel the outputs are
+    /// sent to.
+    fn send_velocity_controllers(
+        &self,
+        context: &mut impl EventSink,
+        timing: u32,
+        velocity: f32,
+        outputs: &Outputs,
+    ) {
+        let mode = self.params.velocity_cc_mode.value();
+        if mode == VelocityCcMode::Off {
+            return;
+        }
+
+        for (idx, output) in outputs.iter().enumerate() {
+            if outputs[..idx].iter().any(|other| other.channel == output.channel) {
+                continue;
+            }
+
+            let value = match self.params.velocity_cc_source.value() {
+                VelocityCcSource::Input => velocity,
+                VelocityCcSource::Output => output.velocity,
+            };
+
+            context.send_event(match mode {
+                VelocityCcMode::ChannelPressure => NoteEvent::MidiChannelPressure {
+                    timing,
+                    channel: output.channel,
+                    pressure: value,
+                },
+                _ => NoteEvent::MidiCC {
+                    timing,
+                    channel: output.channel,
+                    cc: self.params.velocity_cc.value() as u8,
+                    value,
+                },
+            })
+        }
+    }
+
+    /// Send the messages of every range that produced some of the outputs, once on each of its
+    /// channels.
+    fn send_pre_note_messages(
+        &mut self,
+        context: &mut impl EventSink,
+        timing: u32,
+        outputs: &Outputs,
+    ) {
+        for (idx, output) in outputs.iter().enumerate() {
+            let Some(range) = output.range else { continue };
+            if outputs[..idx].iter().any(|other| other.range == output.range && other.channel == output.channel) {
+                continue;
+            }
+
+            let range_params = &self.params.ranges[range];
+            if range_params.messages.iter().all(|message| message.kind.value() == MessageKind::Off) {
+                continue;
+            }
+
+            // skip redundant keyswitches if this articulation is already selected
+            let channel = output.channel as usize;
+            if range_params.only_on_change.value() && self.last_articulation[channel] == Some(range) {
+                continue;
+            }
+            self.last_articulation[channel] = Some(range);
+
+            for message in &range_params.messages {
+                let number = message.number.value() as u8;
+                match message.kind.value() {
+                    MessageKind::Off => (),
+                    MessageKind::Keyswitch => {
+                        context.send_event(NoteEvent::NoteOn {
+                            timing,
+                            voice_id: None,
+                            channel: output.channel,
+                            note: number,
+                            velocity: message.value.unmodulated_normalized_value().max(1.0 / 127.0),
+                        });
+                        context.send_event(NoteEvent::NoteOff {
+                            timing,
+                            voice_id: None,
+                            channel: output.channel,
+                            note: number,
+                            velocity: 0.0,
+                        });
+                    },
+                    MessageKind::Cc => context.send_event(NoteEvent::MidiCC {
+                        timing,
+                        channel: output.channel,
+                        cc: number,
+                        value: message.value.unmodulated_normalized_value(),
+                    }),
+                    MessageKind::ProgramChange => context.send_event(NoteEvent::MidiProgramChange {
+                        timing,
+                        channel: output.channel,
+                        program: number,
+                    }),
+                }
+            }
+        }
+    }
+
+    /// The steps of the selected scale as a bit mask, or `None` if output pitches aren't quantized.
+    fn scale_mask(&self) -> Option<u16> {
+        match self.params.scale.value() {
+            ScaleType::Off => None,
+            ScaleType::Custom => Some(
+                self.params.scale_steps
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, step)| step.active.value())
+                    .fold(0, |mask, (idx, _)| mask | 1 << idx)
+            ),
+            scale => Some(scale::scale_mask(scale)),
+        }
+    }
+
+    /// Reset everything that makes the output depend on earlier notes, so the same input produces
+    /// the same output again.
+    fn reset_playback_state(&mut self) {
+        self.rng.reseed(self.params.seed.value() as u64);
+        for round_robin in &mut self.round_robin {
+            round_robin.reset();
+        }
+        self.last_articulation = [None; MAX_CHANNELS];
+        self.rolling = [[0; MAX_CHANNELS]; MAX_RANGES];
+        self.last_hits = [[None; 128]; MAX_CHANNELS];
+    }
+
+    /// Send a NoteOff for every note that is still held and forget about them. Notes that are
+    /// still delayed are dropped, everything else that is delayed is handled right away.
+    fn release_all(&mut self, context: &mut impl EventSink) {
+        for held in self.note_stack.drain() {
+            send_note_offs(context, 0, held.voice_id, &held.outputs, 0.0);
+        }
+
+        for note_off in self.scheduler.drain() {
+            context.send_event(NoteEvent::NoteOff {
+                timing: 0,
+                voice_id: note_off.voice_id,
+                channel: note_off.channel,
+                note: note_off.note,
+                velocity: 0.0,
+            })
+        }
+
+        while let Some(DelayedEvent { event, .. }) = self.delay_line.pop_due(u64::MAX) {
+            if !matches!(event, NoteEvent::NoteOn { .. } | NoteEvent::NoteOff { .. }) {
+                self.handle_event(with_timing(event, 0), context);
+            }
+        }
+    }
+}
+
+fn send_note_off(
+    context: &mut impl EventSink,
+    timing: u32,
+    voice_id: Option<i32>,
+    output: &OutputNote,
+    velocity: f32,
+) {
+    context.send_event(NoteEvent::NoteOff {
+        timing,
+        voice_id,
+        channel: output.channel,
+        note: output.note,
+        velocity,
+    })
+}
+
+/// Send a choke, unless this channel and pitch is already marked in `choked_notes`.
+fn send_choke(
+    choked_notes: &mut [u128; MAX_CHANNELS],
+    context: &mut impl EventSink,
+    timing: u32,
+    voice_id: Option<i32>,
+    channel: u8,
+    note: u8,
+    mode: ChokeMode,
+) {
+    let bit = 1u128 << note;
+    if choked_notes[channel as usize] & bit != 0 {
+        return;
+    }
+    choked_notes[channel as usize] |= bit;
+
+    context.send_event(match mode {
+        ChokeMode::NoteOff => NoteEvent::NoteOff { timing, voice_id, channel, note, velocity: 0.0 },
+        ChokeMode::Choke => NoteEvent::Choke { timing, voice_id, channel, note },
+    })
+}
+
+fn send_note_offs(
+    context: &mut impl EventSink,
+    timing: u32,
+    voice_id: Option<i32>,
+    outputs: &Outputs,
+    velocity: f32,
+) {
+    for output in outputs {
+        send_note_off(context, timing, voice_id, output, velocity);
+    }
+}
+
+/// Returns the event sent to another note, for events that belong to a single note.
+fn retarget(mut event: NoteEvent<()>, output: &OutputNote) -> NoteEvent<()> {
+    match &mut event {
+        NoteEvent::Choke { channel, note, .. }
+        | NoteEvent::VoiceTerminated { channel, note, .. }
+        | NoteEvent::PolyPressure { channel, note, .. }
+        | NoteEvent::PolyVolume { channel, note, .. }
+        | NoteEvent::PolyPan { channel, note, .. }
+        | NoteEvent::PolyTuning { channel, note, .. }
+        | NoteEvent::PolyVibrato { channel, note, .. }
+        | NoteEvent::PolyExpression { channel, note, .. }
+        | NoteEvent::PolyBrightness { channel, note, .. } => {
+            *channel = output.channel;
+            *note = output.note;
+        },
+        _ => (),
+    }
+    event
+}
+
+/// How a double trigger is handled.
+enum DoubleTrigger {
+    /// Drop the later NoteOn.
+    Drop,
+    /// Release the earlier note and play the later, louder one instead.
+    ReplaceEarlier,
+}
+
+/// When a NoteOn arrived, in samples since the plugin was created, and how hard it was played.
+#[derive(Debug, Clone, Copy)]
+struct LastNoteOn {
+    at: u64,
+    velocity: f32,
 }
 
-fn matches_range(velocity: f32, range_params: &RangeParams) -> Option<u8> {
+/// Returns the event moved to another sample of the current buffer. Events that can't be sent to
+/// the output keep their timing.
+fn with_timing(mut event: NoteEvent<()>, new_timing: u32) -> NoteEvent<()> {
+    match &mut event {
+        NoteEvent::NoteOn { timing, .. }
+        | NoteEvent::NoteOff { timing, .. }
+        | NoteEvent::Choke { timing, .. }
+        | NoteEvent::VoiceTerminated { timing, .. }
+        | NoteEvent::PolyPressure { timing, .. }
+        | NoteEvent::PolyVolume { timing, .. }
+        | NoteEvent::PolyPan { timing, .. }
+        | NoteEvent::PolyTuning { timing, .. }
+        | NoteEvent::PolyVibrato { timing, .. }
+        | NoteEvent::PolyExpression { timing, .. }
+        | NoteEvent::PolyBrightness { timing, .. }
+        | NoteEvent::MidiChannelPressure { timing, .. }
+        | NoteEvent::MidiPitchBend { timing, .. }
+        | NoteEvent::MidiCC { timing, .. }
+        | NoteEvent::MidiProgramChange { timing, .. } => *timing = new_timing,
+        _ => (),
+    }
+    event
+}
+
+/// Everything a range can match on.
+#[derive(Debug, Clone, Copy)]
+struct Hit {
+    /// Whether this is a NoteOn, or a NoteOff with its release velocity or its held time.
+    trigger: Trigger,
+    channel: u8,
+    note: u8,
+    velocity: f32,
+    /// How long the note was held, only used for [`Trigger::Release`].
+    held_ms: f32,
+    /// The last value of the pedal CC on this channel.
+    pedal: f32,
+}
+
+/// `rolling` is whether the range currently sees the hit's pitch as played in a roll.
+fn matches_range(hit: &Hit, range_params: &RangeParams, rolling: bool) -> bool {
+    let Hit { trigger, channel, note, velocity, held_ms, pedal } = *hit;
     let lo = range_params.range_min.unmodulated_normalized_value();
     let hi = range_params.range_max.unmodulated_normalized_value();
+    let key_lo = range_params.key_min.value().min(range_params.key_max.value());
+    let key_hi = range_params.key_min.value().max(range_params.key_max.value());
+    // 0 means any channel, the others are 1-based
+    let input_channel = range_params.input_channel.value();
+
+    !range_params.bypass.value()
+        && range_params.trigger.value() == trigger
+        && (velocity >= lo.min(hi) && velocity <= lo.max(hi))
+        && (key_lo..=key_hi).contains(&(note as i32))
+        && (input_channel == 0 || input_channel == channel as i32 + 1)
+        && (trigger != Trigger::Release || matches_held_time(held_ms, range_params))
+        && matches_pedal(pedal, range_params)
+        && matches_roll(rolling, range_params)
+}
+
+fn matches_roll(rolling: bool, range_params: &RangeParams) -> bool {
+    match range_params.roll.value() {
+        RollCondition::Any => true,
+        RollCondition::Roll => rolling,
+        RollCondition::NoRoll => !rolling,
+    }
+}
+
+fn matches_pedal(pedal: f32, range_params: &RangeParams) -> bool {
+    let lo = range_params.pedal_min.unmodulated_normalized_value();
+    let hi = range_params.pedal_max.unmodulated_normalized_value();
+
+    pedal >= lo.min(hi) && pedal <= lo.max(hi)
+}
+
+fn matches_held_time(held_ms: f32, range_params: &RangeParams) -> bool {
+    let lo = range_params.held_min.value();
+    let hi = range_params.held_max.value();
 
-    if !range_params.bypass.value()
-        && (velocity >= lo.min(hi) && velocity <= lo.max(hi)) {
-        Some(range_params.pitch.value() as u8)
-    } else { return None }
+    held_ms >= lo.min(hi) && held_ms <= lo.max(hi)
+}
+
+/// The size of a range's velocity window, used to find the narrowest match.
+fn range_width(range_params: &RangeParams) -> i32 {
+    (range_params.range_max.value() - range_params.range_min.value()).abs()
+}
+
+/// The velocity a range sends its notes out with.
+fn output_velocity(velocity: f32, range_params: &RangeParams) -> f32 {
+    let lo = range_params.range_min.unmodulated_normalized_value();
+    let hi = range_params.range_max.unmodulated_normalized_value();
+    let out_lo = range_params.out_velocity_min.unmodulated_normalized_value();
+    let out_hi = range_params.out_velocity_max.unmodulated_normalized_value();
+
+    // where in the range's velocity span this note is, from 0 to 1
+    let position = if lo == hi {
+        1.0
+    } else {
+        ((velocity - lo.min(hi)) / (lo - hi).abs()).clamp(0.0, 1.0)
+    };
+
+    let new_velocity = match range_params.velocity_mode.value() {
+        VelocityMode::PassThrough => velocity,
+        VelocityMode::Fixed => range_params.fixed_velocity.unmodulated_normalized_value(),
+        VelocityMode::Rescale => out_lo + position * (out_hi - out_lo),
+        VelocityMode::Curve => {
+            // positive curves push quiet notes up (log), negative ones push them down (exp)
+            let exponent = 4.0f32.powf(-range_params.velocity_curve.value());
+            out_lo + position.powf(exponent) * (out_hi - out_lo)
+        },
+    };
+
+    // a NoteOn with a velocity of 0 would be a NoteOff
+    new_velocity.clamp(1.0 / 127.0, 1.0)
+}
+
+/// The weights of the pitches returned by [`output_pitches`], in the same order.
+fn output_weights(range_params: &RangeParams) -> impl Iterator<Item = f32> + '_ {
+    std::iter::once(range_params.weight.value()).chain(
+        range_params.layers
+            .iter()
+            .filter(|layer| layer.active.value())
+            .map(|layer| layer.weight.value())
+    )
+}
+
+/// The channels a range sends its notes to, the main output channel followed by the extra ones.
+fn output_channels(range_params: &RangeParams, channel: u8) -> impl Iterator<Item = u8> + '_ {
+    let output_channel = match range_params.output_channel.value() {
+        0 => channel,
+        output_channel => (output_channel - 1) as u8,
+    };
+
+    std::iter::once(output_channel).chain(
+        range_params.extra_channels
+            .iter()
+            .map(|extra| extra.channel.value())
+            .filter(|&extra| extra != 0)
+            .map(|extra| (extra - 1) as u8)
+    )
+}
+
+/// The main output pitch of a range, followed by the pitches of its active layers. When
+/// transposing, the main pitch is the shifted incoming pitch and the layers keep their interval to it.
+fn output_pitches(range_params: &RangeParams, note: u8) -> impl Iterator<Item = u8> + '_ {
+    let pitch = range_params.pitch.value();
+    let offset = match range_params.pitch_mode.value() {
+        PitchMode::Absolute => 0,
+        PitchMode::Transpose => note as i32 + range_params.transpose.value() - pitch,
+    };
+
+    std::iter::once(pitch).chain(
+        range_params.layers
+            .iter()
+            .filter(|layer| layer.active.value())
+            .map(|layer| layer.pitch.value())
+    )
+        .map(move |pitch| (pitch + offset).clamp(0, 127) as u8)
 }
 
 impl Plugin for VelocityMapper {
@@ -96,71 +1127,90 @@ impl Plugin for VelocityMapper {
         editor::create(
             self.params.clone(),
             self.params.editor_state.clone(),
+            self.panic.clone(),
         )
     }
 
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
-        _buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        buffer_config: &BufferConfig,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+        self.latency = self.combine_window() as u32;
+        context.set_latency_samples(self.latency);
         true
     }
 
+    fn reset(&mut self) {
+        // we can't send events from here, so this happens in the next process call
+        self.flush_pending = true;
+        self.reset_playback_state();
+    }
+
+    fn deactivate(&mut self) {
+        self.flush_pending = true;
+    }
+
     fn process(
         &mut self,
-        _buffer: &mut Buffer,
+        buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
+        let playing = context.transport().playing;
+        let started = !self.was_playing && playing;
+        let stopped = self.was_playing && !playing;
+        self.was_playing = playing;
+
+        // start every render with the same round robin, random and articulation state
+        if started {
+            self.reset_playback_state();
+        }
+
+        if self.panic.swap(false, Ordering::Relaxed) {
+            self.flush_pending = true;
+        }
+
+        // release everything when playback stops, after a reset or when the panic button was pressed
+        if stopped || self.flush_pending {
+            self.release_all(context);
+            self.flush_pending = false;
+        }
+
+        // a host reporting a tempo of 0 would make note value gates infinitely long
+        self.tempo = context.transport().tempo.unwrap_or(120.0).max(1.0);
+
+        let latency = self.combine_window() as u32;
+        if latency != self.latency {
+            self.latency = latency;
+            context.set_latency_samples(latency);
+        }
 
         while let Some(event) = context.next_event() {
-            match event {
-                NoteEvent::NoteOn {
-                    timing,
-                    voice_id,
-                    channel,
-                    note,
-                    velocity,
-                } => {
-                    let new_pitch = self.get_remapped_pitch(velocity);
-                    // remember this NoteOn and which pitch it was mapped to
-                    self.push_note_on(channel, note, new_pitch.unwrap_or(note));
-
-                    // Send and maybe remap the NoteOn
-                    if let Some(new_pitch) = new_pitch {
-                        context.send_event(NoteEvent::NoteOn {
-                            timing,
-                            voice_id,
-                            channel,
-                            note: new_pitch,
-                            velocity,
-                        })
-                    } else { context.send_event(event) }
-                },
-                NoteEvent::NoteOff {
-                    timing,
-                    voice_id,
-                    channel,
-                    note,
-                    velocity,
-                } => {
-                    // if we remapped the last NoteOn on this channel with this pitch, get new pitch
-                    let new_pitch = self.pop_note_on(channel, note).unwrap_or(note);
-
-                    context.send_event(NoteEvent::NoteOff {
-                        timing,
-                        voice_id,
-                        channel,
-                        note: new_pitch,
-                        velocity,
-                    })
-                },
-                _ => (),
+            let now = self.sample_pos + event.timing() as u64;
+
+            // hold every event back by the latency, so later NoteOns can still be combined with it.
+            // After the latency was lowered, events keep going through the delay line until it is
+            // empty, and never overtake the events that are still in there.
+            let due = (now + self.latency as u64).max(self.delay_line.last_due().unwrap_or(0));
+            let delayed = (self.latency > 0 || !self.delay_line.is_empty())
+                && self.delay_line.push(DelayedEvent { due, event });
+
+            // delayed events and scheduled NoteOffs that are due at or before this event come first
+            self.handle_delayed_events(context, now + 1);
+            if !delayed {
+                self.send_due_note_offs(context, now + 1);
+                self.handle_event(event, context);
             }
         }
 
+        let buffer_end = self.sample_pos + buffer.samples() as u64;
+        self.handle_delayed_events(context, buffer_end);
+        self.send_due_note_offs(context, buffer_end);
+        self.sample_pos = buffer_end;
+
         ProcessStatus::Normal
     }
 }
@@ -185,4 +1235,178 @@ impl Vst3Plugin for VelocityMapper {
 }
 
 nih_export_clap!(VelocityMapper);
-nih_export_vst3!(VelocityMapper);
\ No newline at end of file
+nih_export_vst3!(VelocityMapper);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::NoteOffMatching;
+
+    /// Collects the events the plugin sends.
+    #[derive(Default)]
+    struct Events(Vec<NoteEvent<()>>);
+
+    impl EventSink for Events {
+        fn send_event(&mut self, event: NoteEvent<()>) {
+            self.0.push(event)
+        }
+    }
+
+    impl Events {
+        /// The timing and pitch of every NoteOff that was sent.
+        fn note_offs(&self) -> Vec<(u32, u8)> {
+            self.0
+                .iter()
+                .filter_map(|event| match *event {
+                    NoteEvent::NoteOff { timing, note, .. } => Some((timing, note)),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    fn mapper(params: VelocityMapperParams) -> VelocityMapper {
+        VelocityMapper {
+            params: Arc::new(params),
+            ..VelocityMapper::default()
+        }
+    }
+
+    fn note_on(timing: u32, note: u8, velocity: f32) -> NoteEvent<()> {
+        NoteEvent::NoteOn { timing, voice_id: None, channel: 0, note, velocity }
+    }
+
+    fn note_off(timing: u32, note: u8) -> NoteEvent<()> {
+        NoteEvent::NoteOff { timing, voice_id: None, channel: 0, note, velocity: 0.0 }
+    }
+
+    /// Make a range map the velocities from `min` to `max` to `pitch`.
+    fn remap(range: &mut RangeParams, min: i32, max: i32, pitch: i32) {
+        range.bypass = BoolParam::new("Bypass", false);
+        range.range_min = IntParam::new("Velocity Minimum", min, IntRange::Linear { min: 0, max: 127 });
+        range.range_max = IntParam::new("Velocity Maximum", max, IntRange::Linear { min: 0, max: 127 });
+        range.pitch = IntParam::new("Output Pitch", pitch, IntRange::Linear { min: 0, max: 127 });
+    }
+
+    #[test]
+    fn choked_note_is_not_paired_with_a_later_note_off() {
+        let mut params = VelocityMapperParams::default();
+        params.note_off_matching = EnumParam::new("Note-Off Matching", NoteOffMatching::Fifo);
+        remap(&mut params.ranges[0], 0, 63, 36);
+        remap(&mut params.ranges[1], 64, 127, 38);
+        let mut mapper = mapper(params);
+        let mut events = Events::default();
+
+        mapper.handle_event(note_on(0, 60, 0.3), &mut events);
+        mapper.handle_event(NoteEvent::Choke { timing: 10, voice_id: None, channel: 0, note: 60 }, &mut events);
+        mapper.handle_event(note_on(20, 60, 0.9), &mut events);
+        mapper.handle_event(note_off(30, 60), &mut events);
+
+        assert!(events.0.contains(&NoteEvent::Choke { timing: 10, voice_id: None, channel: 0, note: 36 }));
+        assert_eq!(events.note_offs(), [(30, 38)]);
+    }
+
+    /// Hit a pitch `interval_ms` after its last hit on this channel, and return whether the first
+    /// range sees it as rolling afterwards.
+    fn roll_after(mapper: &mut VelocityMapper, last_hit: &mut u64, channel: u8, interval_ms: u64) -> bool {
+        *last_hit += interval_ms * 441 / 10;
+        mapper.update_rolls(channel, 60, *last_hit);
+        mapper.is_rolling(0, channel, 60)
+    }
+
+    #[test]
+    fn rolls_use_hysteresis() {
+        // with the default interval of 120 ms and hysteresis of 0.2, a roll starts below 96 ms
+        // and ends at 144 ms
+        let mut mapper = VelocityMapper::default();
+        let mut last_hit = 0;
+
+        assert!(!roll_after(&mut mapper, &mut last_hit, 0, 0));
+        assert!(!roll_after(&mut mapper, &mut last_hit, 0, 100));
+        assert!(roll_after(&mut mapper, &mut last_hit, 0, 90));
+        assert!(roll_after(&mut mapper, &mut last_hit, 0, 140));
+        assert!(roll_after(&mut mapper, &mut last_hit, 0, 100));
+        assert!(!roll_after(&mut mapper, &mut last_hit, 0, 150));
+        assert!(!roll_after(&mut mapper, &mut last_hit, 0, 140));
+        assert!(!roll_after(&mut mapper, &mut last_hit, 0, 100));
+        assert!(roll_after(&mut mapper, &mut last_hit, 0, 90));
+    }
+
+    #[test]
+    fn rolls_are_tracked_per_channel() {
+        let mut mapper = VelocityMapper::default();
+        let mut last_hit = 0;
+        roll_after(&mut mapper, &mut last_hit, 0, 0);
+        assert!(roll_after(&mut mapper, &mut last_hit, 0, 50));
+
+        // the first hit on another channel is not part of the roll on channel 0
+        let mut other_last_hit = last_hit;
+        assert!(!roll_after(&mut mapper, &mut other_last_hit, 1, 10));
+        assert!(!roll_after(&mut mapper, &mut other_last_hit, 1, 200));
+        assert!(mapper.is_rolling(0, 0, 60));
+
+        // and ending the roll on channel 0 leaves a roll on channel 1 alone
+        assert!(roll_after(&mut mapper, &mut other_last_hit, 1, 50));
+        assert!(!roll_after(&mut mapper, &mut last_hit, 0, 500));
+        assert!(mapper.is_rolling(0, 1, 60));
+    }
+
+    /// Play a pitch twice within the retrigger window, with the given velocities, then release
+    /// it twice.
+    fn double_trigger(mode: RetriggerMode, matching: NoteOffMatching, first: f32, second: f32) -> Events {
+        let mut params = VelocityMapperParams::default();
+        params.retrigger_ms = FloatParam::new("Retrigger Window", 10.0, FloatRange::Linear { min: 0.0, max: 100.0 });
+        params.retrigger_mode = EnumParam::new("Retrigger Mode", mode);
+        params.note_off_matching = EnumParam::new("Note-Off Matching", matching);
+        let mut mapper = mapper(params);
+        let mut events = Events::default();
+
+        mapper.handle_event(note_on(0, 60, first), &mut events);
+        mapper.handle_event(note_on(100, 60, second), &mut events);
+        mapper.handle_event(note_off(1000, 60), &mut events);
+        mapper.handle_event(note_off(2000, 60), &mut events);
+        events
+    }
+
+    fn note_on_timings(events: &Events) -> Vec<u32> {
+        events.0
+            .iter()
+            .filter_map(|event| match *event {
+                NoteEvent::NoteOn { timing, .. } => Some(timing),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_second_sends_one_note_off() {
+        for matching in [NoteOffMatching::Fifo, NoteOffMatching::Lifo] {
+            let events = double_trigger(RetriggerMode::DropSecond, matching, 0.5, 0.8);
+            assert_eq!(note_on_timings(&events), [0]);
+            assert_eq!(events.note_offs(), [(2000, 60)]);
+        }
+    }
+
+    #[test]
+    fn keep_louder_drops_a_quieter_second_note() {
+        for matching in [NoteOffMatching::Fifo, NoteOffMatching::Lifo] {
+            let events = double_trigger(RetriggerMode::KeepLouder, matching, 0.8, 0.5);
+            assert_eq!(note_on_timings(&events), [0]);
+            assert_eq!(events.note_offs(), [(2000, 60)]);
+        }
+    }
+
+    #[test]
+    fn keep_louder_replaces_a_quieter_first_note() {
+        for matching in [NoteOffMatching::Fifo, NoteOffMatching::Lifo] {
+            let events = double_trigger(RetriggerMode::KeepLouder, matching, 0.5, 0.8);
+            assert_eq!(note_on_timings(&events), [0, 100]);
+
+            // the first note is released when the louder one replaces it
+            let note_offs = events.note_offs();
+            let (replaced, released) = note_offs.split_at(1);
+            assert_eq!(replaced, [(100, 60)]);
+            // and the two incoming NoteOffs release the louder note only once, with the last one
+            assert_eq!(released, [(2000, 60)]);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use smallvec::SmallVec;
+use crate::params::NoteOffMatching;
+
+/// The maximum number of NoteOns that can wait for their NoteOff at the same time.
+pub const MAX_HELD_NOTES: usize = 1024;
+
+/// The maximum number of notes a single NoteOn can be sent out as.
+pub const MAX_OUTPUT_NOTES: usize = 32;
+
+/// A note that was sent out in place of an incoming NoteOn.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputNote {
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: f32,
+    /// The range that produced this note, `None` if the NoteOn was passed through.
+    pub range: Option<usize>,
+}
+
+/// Stored inline, so this doesn't allocate as long as it doesn't grow beyond `MAX_OUTPUT_NOTES`.
+pub type Outputs = SmallVec<[OutputNote; MAX_OUTPUT_NOTES]>;
+
+/// Add a note to the outputs, unless it is already in there or there is no space left.
+pub fn push_output(outputs: &mut Outputs, output: OutputNote) {
+    let duplicate = outputs
+        .iter()
+        .any(|other| other.channel == output.channel && other.note == output.note);
+
+    if outputs.len() < MAX_OUTPUT_NOTES && !duplicate {
+        outputs.push(output);
+    }
+}
+
+/// A NoteOn that is still waiting for its NoteOff.
+#[derive(Debug, Clone)]
+pub struct HeldNote {
+    pub channel: u8,
+    pub note: u8,
+    pub voice_id: Option<i32>,
+    pub velocity: f32,
+    /// The sample this NoteOn arrived at, counted from when the plugin was created.
+    pub started_at: u64,
+    /// The notes this NoteOn was sent out as.
+    pub outputs: Outputs,
+    /// Whether this NoteOn was filtered out, e.g. as crosstalk. Its NoteOff is swallowed and
+    /// doesn't trigger anything either.
+    pub suppressed: bool,
+    /// The NoteOffs of double triggers that were folded into this note. Each of them is swallowed,
+    /// the note is released by the NoteOff after them.
+    pub folded_note_offs: u32,
+    /// Increases with every NoteOn, used to find the oldest or newest matching note.
+    order: u64,
+}
+
+/// Keeps track of all NoteOns that have not been released yet, so every NoteOff (and every
+/// polyphonic event) can be sent to the notes its NoteOn was remapped to. All storage is allocated
+/// up front, so this never allocates on the audio thread.
+pub struct NoteStack {
+    notes: Vec<HeldNote>,
+    next_order: u64,
+}
+
+impl Default for NoteStack {
+    fn default() -> Self {
+        Self {
+            notes: Vec::with_capacity(MAX_HELD_NOTES),
+            next_order: 0,
+        }
+    }
+}
+
+impl NoteStack {
+    /// Remember a NoteOn. If there is no space left, the oldest note is evicted and returned, so it
+    /// can be released instead of getting stuck.
+    pub fn push(
+        &mut self,
+        channel: u8,
+        note: u8,
+        voice_id: Option<i32>,
+        velocity: f32,
+        started_at: u64,
+        outputs: Outputs,
+    ) -> Option<HeldNote> {
+        self.insert(HeldNote {
+            channel,
+            note,
+            voice_id,
+            velocity,
+            started_at,
+            outputs,
+            suppressed: false,
+            folded_note_offs: 0,
+            order: 0,
+        })
+    }
+
+    /// Remember a NoteOn that was filtered out, so its NoteOff can be swallowed.
+    pub fn push_suppressed(
+        &mut self,
+        channel: u8,
+        note: u8,
+        voice_id: Option<i32>,
+        velocity: f32,
+        started_at: u64,
+    ) -> Option<HeldNote> {
+        self.insert(HeldNote {
+            channel,
+            note,
+            voice_id,
+            velocity,
+            started_at,
+            outputs: Outputs::new(),
+            suppressed: true,
+            folded_note_offs: 0,
+            order: 0,
+        })
+    }
+
+    /// Remove and return the newest held note with this channel and pitch.
+    pub fn pop_newest(&mut self, channel: u8, note: u8) -> Option<HeldNote> {
+        self.pop(channel, note, None, NoteOffMatching::Lifo)
+    }
+
+    /// Make the newest held note with this channel and pitch wait for `count` more NoteOffs.
+    /// Returns `false` if there is no such note.
+    pub fn fold_note_offs(&mut self, channel: u8, note: u8, count: u32) -> bool {
+        match self.find(channel, note, None, NoteOffMatching::Lifo) {
+            Some(idx) => {
+                self.notes[idx].folded_note_offs += count;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Swallow a NoteOff if the held note it belongs to is still waiting for folded NoteOffs.
+    pub fn swallow_folded_note_off(&mut self, channel: u8, note: u8, voice_id: Option<i32>, matching: NoteOffMatching) -> bool {
+        match self.find(channel, note, voice_id, matching) {
+            Some(idx) if self.notes[idx].folded_note_offs > 0 => {
+                self.notes[idx].folded_note_offs -= 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// The held note an event with this channel, pitch and voice ID belongs to.
+    pub fn peek(&self, channel: u8, note: u8, voice_id: Option<i32>, matching: NoteOffMatching) -> Option<&HeldNote> {
+        self.find(channel, note, voice_id, matching).map(|idx| &self.notes[idx])
+    }
+
+    /// Remove and return the held note a NoteOff with this channel, pitch and voice ID releases.
+    pub fn pop(&mut self, channel: u8, note: u8, voice_id: Option<i32>, matching: NoteOffMatching) -> Option<HeldNote> {
+        self.find(channel, note, voice_id, matching).map(|idx| self.notes.swap_remove(idx))
+    }
+
+    /// Remove outputs from all held notes. `remove` is called with the voice ID of the held note
+    /// and each of its outputs, and returns whether that output should be removed.
+    pub fn remove_outputs(&mut self, mut remove: impl FnMut(Option<i32>, &OutputNote) -> bool) {
+        for held in &mut self.notes {
+            let voice_id = held.voice_id;
+            held.outputs.retain(|output| !remove(voice_id, output));
+        }
+    }
+
+    /// Remove all held notes, e.g. to release them when playback stops.
+    pub fn drain(&mut self) -> impl Iterator<Item = HeldNote> + '_ {
+        self.notes.drain(..)
+    }
+
+    fn insert(&mut self, mut held: HeldNote) -> Option<HeldNote> {
+        let evicted = if self.notes.len() >= MAX_HELD_NOTES {
+            self.oldest_index().map(|idx| self.notes.swap_remove(idx))
+        } else { None };
+
+        held.order = self.next_order;
+        self.notes.push(held);
+        self.next_order += 1;
+
+        evicted
+    }
+
+    fn find(&self, channel: u8, note: u8, voice_id: Option<i32>, matching: NoteOffMatching) -> Option<usize> {
+        // if the host tells us which voice this is, that always wins
+        if let Some(voice_id) = voice_id {
+            let idx = self.notes.iter().position(|held| held.voice_id == Some(voice_id));
+            if idx.is_some() {
+                return idx;
+            }
+        }
+
+        let candidates = self.notes
+            .iter()
+            .enumerate()
+            .filter(|(_, held)| held.channel == channel && held.note == note);
+
+        match matching {
+            NoteOffMatching::Fifo => candidates.min_by_key(|(_, held)| held.order),
+            NoteOffMatching::Lifo => candidates.max_by_key(|(_, held)| held.order),
+        }
+            .map(|(idx, _)| idx)
+    }
+
+    fn oldest_index(&self) -> Option<usize> {
+        self.notes
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, held)| held.order)
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(note: u8) -> Outputs {
+        let mut outputs = Outputs::new();
+        push_output(&mut outputs, OutputNote { channel: 0, note, velocity: 1.0, range: None });
+        outputs
+    }
+
+    #[test]
+    fn voice_id_wins_over_matching() {
+        let mut stack = NoteStack::default();
+        stack.push(0, 60, Some(1), 1.0, 0, outputs(61));
+        stack.push(0, 60, Some(2), 1.0, 1, outputs(62));
+
+        let held = stack.pop(0, 60, Some(1), NoteOffMatching::Lifo).unwrap();
+        assert_eq!(held.outputs[0].note, 61);
+        let held = stack.pop(0, 60, Some(2), NoteOffMatching::Fifo).unwrap();
+        assert_eq!(held.outputs[0].note, 62);
+    }
+
+    #[test]
+    fn unknown_voice_id_falls_back_to_pitch() {
+        let mut stack = NoteStack::default();
+        stack.push(0, 60, Some(1), 1.0, 0, outputs(61));
+
+        let held = stack.pop(0, 60, Some(7), NoteOffMatching::Lifo).unwrap();
+        assert_eq!(held.outputs[0].note, 61);
+    }
+
+    #[test]
+    fn fifo_and_lifo_pairing() {
+        let mut stack = NoteStack::default();
+        for note in 61..=63 {
+            stack.push(0, 60, None, 1.0, 0, outputs(note));
+        }
+
+        assert_eq!(stack.pop(0, 60, None, NoteOffMatching::Fifo).unwrap().outputs[0].note, 61);
+        assert_eq!(stack.pop(0, 60, None, NoteOffMatching::Lifo).unwrap().outputs[0].note, 63);
+        assert_eq!(stack.pop(0, 60, None, NoteOffMatching::Fifo).unwrap().outputs[0].note, 62);
+        assert!(stack.pop(0, 60, None, NoteOffMatching::Fifo).is_none());
+    }
+
+    #[test]
+    fn pairing_respects_channel_and_pitch() {
+        let mut stack = NoteStack::default();
+        stack.push(0, 60, None, 1.0, 0, outputs(61));
+        stack.push(1, 60, None, 1.0, 0, outputs(62));
+
+        assert!(stack.pop(0, 59, None, NoteOffMatching::Lifo).is_none());
+        assert_eq!(stack.pop(1, 60, None, NoteOffMatching::Lifo).unwrap().outputs[0].note, 62);
+        assert_eq!(stack.pop(0, 60, None, NoteOffMatching::Lifo).unwrap().outputs[0].note, 61);
+    }
+
+    #[test]
+    fn full_stack_evicts_the_oldest_note() {
+        let mut stack = NoteStack::default();
+        for idx in 0..MAX_HELD_NOTES {
+            assert!(stack.push(0, (idx % 128) as u8, None, 1.0, idx as u64, Outputs::new()).is_none());
+        }
+
+        let evicted = stack.push(0, 0, None, 1.0, MAX_HELD_NOTES as u64, Outputs::new()).unwrap();
+        assert_eq!(evicted.started_at, 0);
+
+        // every other note can still be paired
+        let mut count = 0;
+        while stack.pop(0, (count % 128) as u8, None, NoteOffMatching::Fifo).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, MAX_HELD_NOTES);
+    }
+
+    #[test]
+    fn folded_note_offs_are_swallowed_first() {
+        let mut stack = NoteStack::default();
+        stack.push(0, 60, Some(1), 1.0, 0, outputs(61));
+        assert!(stack.fold_note_offs(0, 60, 1));
+
+        assert!(stack.swallow_folded_note_off(0, 60, Some(1), NoteOffMatching::Lifo));
+        assert!(!stack.swallow_folded_note_off(0, 60, Some(2), NoteOffMatching::Lifo));
+        assert_eq!(stack.pop(0, 60, Some(2), NoteOffMatching::Lifo).unwrap().outputs[0].note, 61);
+        assert!(!stack.fold_note_offs(0, 60, 1));
+    }
+
+    #[test]
+    fn push_output_skips_duplicates() {
+        let mut outputs = outputs(61);
+        push_output(&mut outputs, OutputNote { channel: 0, note: 61, velocity: 0.5, range: Some(0) });
+        push_output(&mut outputs, OutputNote { channel: 1, note: 61, velocity: 0.5, range: Some(0) });
+
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].velocity, 1.0);
+    }
+}
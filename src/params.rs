@@ -2,33 +2,579 @@ use std::sync::Arc;
 use nih_plug::prelude::*;
 use vizia_plug::ViziaState;
 use crate::editor;
+use crate::scale::{NOTE_NAMES, SCALE_STEPS};
+
+/// The maximum number of velocity ranges, only the first `how_many` of them are used.
+pub const MAX_RANGES: usize = 32;
+
+/// The number of additional pitches each range can layer on top of its main pitch.
+pub const MAX_LAYERS: usize = 3;
+
+/// The number of additional channels each range can send its notes to.
+pub const MAX_EXTRA_CHANNELS: usize = 3;
+
+/// The number of messages each range can send before its notes.
+pub const MAX_MESSAGES: usize = 4;
+
+/// The number of pad groups the crosstalk filter compares notes in.
+pub const MAX_CROSSTALK_GROUPS: usize = 4;
+
+/// The number of pads in each crosstalk group.
+pub const MAX_GROUP_PADS: usize = 8;
+
+/// The number of rules that combine two simultaneous hits into one note.
+pub const MAX_COMBINE_RULES: usize = 4;
 
 #[derive(Params)]
 pub struct VelocityMapperParams {
     #[persist = "editor-state"]
     pub editor_state: Arc<ViziaState>,
 
-    // TODO can we have a flexible amount of ranges??
-    // #[id = "how_many"]
-    // pub how_many: IntParam,
+    #[id = "how_many"]
+    pub how_many: IntParam,
+
+    #[id = "note_off_matching"]
+    pub note_off_matching: EnumParam<NoteOffMatching>,
+
+    #[id = "overlap"]
+    pub overlap: EnumParam<OverlapMode>,
+
+    #[id = "unmatched"]
+    pub unmatched: EnumParam<UnmatchedMode>,
+
+    /// The same seed and the same input always produce the same output.
+    #[id = "seed"]
+    pub seed: IntParam,
+
+    #[id = "scale"]
+    pub scale: EnumParam<ScaleType>,
+
+    #[id = "scale_root"]
+    pub scale_root: IntParam,
+
+    /// The steps of `ScaleType::Custom`, relative to the root.
+    #[nested(array, group = "scale steps")]
+    pub scale_steps: Vec<ScaleStepParams>,
+
+    #[id = "velocity_cc_mode"]
+    pub velocity_cc_mode: EnumParam<VelocityCcMode>,
+
+    #[id = "velocity_cc"]
+    pub velocity_cc: IntParam,
+
+    #[id = "velocity_cc_source"]
+    pub velocity_cc_source: EnumParam<VelocityCcSource>,
+
+    #[id = "choke_mode"]
+    pub choke_mode: EnumParam<ChokeMode>,
+
+    /// The CC that ranges can match on with their pedal window, e.g. a hi-hat pedal.
+    #[id = "pedal_cc"]
+    pub pedal_cc: IntParam,
+
+    /// A second NoteOn of the same pitch within this time counts as a double trigger, 0 turns
+    /// this off.
+    #[id = "retrigger_ms"]
+    pub retrigger_ms: FloatParam,
+
+    #[id = "retrigger_mode"]
+    pub retrigger_mode: EnumParam<RetriggerMode>,
+
+    /// A NoteOn is dropped as crosstalk if a much louder NoteOn from the same group arrived this
+    /// long before it, 0 turns this off.
+    #[id = "crosstalk_ms"]
+    pub crosstalk_ms: FloatParam,
+
+    /// How quiet compared to the louder note a NoteOn has to be to count as crosstalk.
+    #[id = "crosstalk_ratio"]
+    pub crosstalk_ratio: FloatParam,
+
+    #[nested(array, group = "crosstalk groups")]
+    pub crosstalk_groups: Vec<CrosstalkGroupParams>,
+
+    /// How far apart the two hits of a combine rule can be. Every event is delayed by this much, 0
+    /// turns combining off.
+    #[id = "combine_ms"]
+    pub combine_ms: FloatParam,
+
+    #[nested(array, group = "combine rules")]
+    pub combine_rules: Vec<CombineRuleParams>,
 
     #[nested(array, group = "ranges")]
     pub ranges: Vec<RangeParams>,
 }
 
+/// Which NoteOn a NoteOff releases, when the host doesn't tell us with a voice ID.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum NoteOffMatching {
+    #[name = "Oldest First"]
+    Fifo,
+    #[name = "Newest First"]
+    Lifo,
+}
+
+/// Which ranges are used when several of them match the same note.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum OverlapMode {
+    /// The matching range with the lowest slot number.
+    #[name = "First Match"]
+    First,
+    /// The matching range with the highest slot number.
+    #[name = "Last Match"]
+    Last,
+    /// All matching ranges, their notes are layered.
+    #[name = "All Matches"]
+    All,
+    /// The matching range with the smallest velocity window.
+    #[name = "Narrowest Range"]
+    Narrowest,
+    #[name = "Random"]
+    Random,
+}
+
+/// The scale remapped pitches are snapped to.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum ScaleType {
+    #[name = "Off"]
+    Off,
+    #[name = "Major"]
+    Major,
+    #[name = "Natural Minor"]
+    NaturalMinor,
+    #[name = "Harmonic Minor"]
+    HarmonicMinor,
+    #[name = "Melodic Minor"]
+    MelodicMinor,
+    #[name = "Dorian"]
+    Dorian,
+    #[name = "Phrygian"]
+    Phrygian,
+    #[name = "Lydian"]
+    Lydian,
+    #[name = "Mixolydian"]
+    Mixolydian,
+    #[name = "Locrian"]
+    Locrian,
+    #[name = "Major Pentatonic"]
+    MajorPentatonic,
+    #[name = "Minor Pentatonic"]
+    MinorPentatonic,
+    #[name = "Blues"]
+    Blues,
+    /// Uses the scale steps.
+    #[name = "Custom"]
+    Custom,
+}
+
+#[derive(Params)]
+pub struct ScaleStepParams {
+    #[id = "scale_step"]
+    pub active: BoolParam,
+}
+
+/// Which controller message is sent before each remapped NoteOn.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum VelocityCcMode {
+    #[name = "Off"]
+    Off,
+    #[name = "Control Change"]
+    Cc,
+    #[name = "Channel Pressure"]
+    ChannelPressure,
+}
+
+/// Which velocity the controller message sends.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum VelocityCcSource {
+    #[name = "Incoming Velocity"]
+    Input,
+    /// The velocity the matched range sends its notes out with.
+    #[name = "Range Velocity"]
+    Output,
+}
+
+/// How notes in a choke group are cut off.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum ChokeMode {
+    #[name = "Note Off"]
+    NoteOff,
+    #[name = "Choke Event"]
+    Choke,
+}
+
+/// Which NoteOn of a double trigger is kept.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum RetriggerMode {
+    #[name = "Drop Second"]
+    DropSecond,
+    /// The quieter NoteOn is dropped, if the second one is louder the first one is released.
+    #[name = "Keep Louder"]
+    KeepLouder,
+}
+
+/// What happens to notes that match no range.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum UnmatchedMode {
+    #[name = "Pass Through"]
+    Pass,
+    #[name = "Block"]
+    Block,
+}
+
+/// Which velocity a range matches on.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Trigger {
+    #[name = "Note On"]
+    NoteOn,
+    /// Match the release velocity and send a short note when the key is released.
+    #[name = "Note Off"]
+    NoteOff,
+    /// Match the NoteOn velocity and how long the key was held, and send a short note when the
+    /// key is released.
+    #[name = "Release"]
+    Release,
+}
+
+/// What a range does with the notes it matches.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum RangeAction {
+    #[name = "Remap"]
+    Remap,
+    /// Remove the note entirely, this wins over other ranges matching the same note.
+    #[name = "Drop"]
+    Drop,
+}
+
 #[derive(Params)]
 pub struct RangeParams {
     #[id = "bypass"]
     pub bypass: BoolParam,
 
+    #[id = "action"]
+    pub action: EnumParam<RangeAction>,
+
+    #[id = "trigger"]
+    pub trigger: EnumParam<Trigger>,
+
+    /// The shortest held time matched by [`Trigger::Release`].
+    #[id = "held_min"]
+    pub held_min: FloatParam,
+
+    /// The longest held time matched by [`Trigger::Release`].
+    #[id = "held_max"]
+    pub held_max: FloatParam,
+
     #[id = "range_min"]
     pub range_min: IntParam,
 
     #[id = "range_max"]
     pub range_max: IntParam,
 
+    #[id = "key_min"]
+    pub key_min: IntParam,
+
+    #[id = "key_max"]
+    pub key_max: IntParam,
+
+    /// 0 matches every channel, 1 to 16 only that channel.
+    #[id = "input_channel"]
+    pub input_channel: IntParam,
+
+    #[id = "pedal_min"]
+    pub pedal_min: IntParam,
+
+    #[id = "pedal_max"]
+    pub pedal_max: IntParam,
+
+    #[id = "roll"]
+    pub roll: EnumParam<RollCondition>,
+
+    /// Repeated NoteOns of the same pitch closer together than this count as a roll.
+    #[id = "roll_ms"]
+    pub roll_ms: FloatParam,
+
+    /// How far the interval has to move past `roll_ms` before a roll starts or ends.
+    #[id = "roll_hysteresis"]
+    pub roll_hysteresis: FloatParam,
+
     #[id = "pitch"]
     pub pitch: IntParam,
+
+    #[id = "weight"]
+    pub weight: FloatParam,
+
+    #[id = "probability"]
+    pub probability: FloatParam,
+
+    #[id = "pitch_mode"]
+    pub pitch_mode: EnumParam<PitchMode>,
+
+    /// Snap the output pitches to the scale.
+    #[id = "quantize"]
+    pub quantize: BoolParam,
+
+    #[id = "transpose"]
+    pub transpose: IntParam,
+
+    #[nested(array, group = "layers")]
+    pub layers: Vec<LayerParams>,
+
+    #[id = "pitch_order"]
+    pub pitch_order: EnumParam<PitchOrder>,
+
+    /// 0 keeps the incoming channel, 1 to 16 send to that channel instead.
+    #[id = "output_channel"]
+    pub output_channel: IntParam,
+
+    #[nested(array, group = "extra channels")]
+    pub extra_channels: Vec<ExtraChannelParams>,
+
+    #[id = "velocity_mode"]
+    pub velocity_mode: EnumParam<VelocityMode>,
+
+    #[id = "fixed_velocity"]
+    pub fixed_velocity: IntParam,
+
+    #[id = "out_velocity_min"]
+    pub out_velocity_min: IntParam,
+
+    #[id = "out_velocity_max"]
+    pub out_velocity_max: IntParam,
+
+    #[id = "velocity_curve"]
+    pub velocity_curve: FloatParam,
+
+    /// Sent in order, right before the range's notes.
+    #[nested(array, group = "messages")]
+    pub messages: Vec<MessageParams>,
+
+    /// Only send the messages if a different range sent its messages on that channel last.
+    #[id = "only_on_change"]
+    pub only_on_change: BoolParam,
+
+    #[id = "gate_mode"]
+    pub gate_mode: EnumParam<GateMode>,
+
+    #[id = "gate_unit"]
+    pub gate_unit: EnumParam<GateUnit>,
+
+    #[id = "gate_ms"]
+    pub gate_ms: FloatParam,
+
+    #[id = "gate_note"]
+    pub gate_note: EnumParam<NoteValue>,
+
+    /// 0 is no group. Notes of a range cut off held notes of other ranges in the same group.
+    #[id = "choke_group"]
+    pub choke_group: IntParam,
+}
+
+/// Whether a range matches notes that are played in fast repetition.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum RollCondition {
+    #[name = "Any"]
+    Any,
+    #[name = "Rolls Only"]
+    Roll,
+    #[name = "No Rolls"]
+    NoRoll,
+}
+
+/// How long a range's notes are held.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum GateMode {
+    /// Release the notes with the incoming NoteOff.
+    #[name = "Follow Input"]
+    Off,
+    /// Release the notes after the gate length, the incoming NoteOff is ignored.
+    #[name = "Fixed"]
+    Fixed,
+    /// Hold the notes for at least the gate length.
+    #[name = "Minimum"]
+    Minimum,
+    /// Hold the notes for at most the gate length.
+    #[name = "Maximum"]
+    Maximum,
+}
+
+/// Whether the gate length is set in milliseconds or as a note value.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum GateUnit {
+    #[name = "ms"]
+    Milliseconds,
+    #[name = "Note Value"]
+    NoteValue,
+}
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum NoteValue {
+    #[name = "1/1"]
+    Whole,
+    #[name = "1/2"]
+    Half,
+    #[name = "1/4."]
+    DottedQuarter,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/4T"]
+    QuarterTriplet,
+    #[name = "1/8."]
+    DottedEighth,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/8T"]
+    EighthTriplet,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/16T"]
+    SixteenthTriplet,
+    #[name = "1/32"]
+    ThirtySecond,
+    #[name = "1/64"]
+    SixtyFourth,
+}
+
+impl NoteValue {
+    /// The length of this note value in quarter notes.
+    pub fn beats(self) -> f64 {
+        match self {
+            NoteValue::Whole => 4.0,
+            NoteValue::Half => 2.0,
+            NoteValue::DottedQuarter => 1.5,
+            NoteValue::Quarter => 1.0,
+            NoteValue::QuarterTriplet => 2.0 / 3.0,
+            NoteValue::DottedEighth => 0.75,
+            NoteValue::Eighth => 0.5,
+            NoteValue::EighthTriplet => 1.0 / 3.0,
+            NoteValue::Sixteenth => 0.25,
+            NoteValue::SixteenthTriplet => 1.0 / 6.0,
+            NoteValue::ThirtySecond => 0.125,
+            NoteValue::SixtyFourth => 0.0625,
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct MessageParams {
+    #[id = "message_kind"]
+    pub kind: EnumParam<MessageKind>,
+
+    /// The keyswitch pitch, CC number or program.
+    #[id = "message_number"]
+    pub number: IntParam,
+
+    /// The keyswitch velocity or CC value.
+    #[id = "message_value"]
+    pub value: IntParam,
+}
+
+/// A message a range sends before its notes.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum MessageKind {
+    #[name = "Off"]
+    Off,
+    /// A NoteOn immediately followed by its NoteOff.
+    #[name = "Keyswitch"]
+    Keyswitch,
+    #[name = "Control Change"]
+    Cc,
+    #[name = "Program Change"]
+    ProgramChange,
+}
+
+/// Pads that can trigger each other, e.g. neighbouring drum pads sharing a mount.
+#[derive(Params)]
+pub struct CrosstalkGroupParams {
+    #[nested(array, group = "pads")]
+    pub pads: Vec<PadParams>,
+}
+
+#[derive(Params)]
+pub struct PadParams {
+    /// The input pitch of this pad, -1 is off.
+    #[id = "pad_pitch"]
+    pub pitch: IntParam,
+}
+
+/// Two input pitches that are played as a third one when they are hit together, e.g. the head and
+/// rim of a snare pad as a rimshot. The combined note gets the louder of the two velocities.
+#[derive(Params)]
+pub struct CombineRuleParams {
+    #[id = "combine_active"]
+    pub active: BoolParam,
+
+    #[id = "combine_pitch_a"]
+    pub pitch_a: IntParam,
+
+    #[id = "combine_pitch_b"]
+    pub pitch_b: IntParam,
+
+    #[id = "combine_output"]
+    pub output: IntParam,
+}
+
+#[derive(Params)]
+pub struct ExtraChannelParams {
+    /// 0 is off, 1 to 16 also send to that channel.
+    #[id = "extra_channel"]
+    pub channel: IntParam,
+}
+
+/// How a range computes its output pitch.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum PitchMode {
+    /// Replace the incoming pitch with the range's pitch.
+    #[name = "Absolute"]
+    Absolute,
+    /// Shift the incoming pitch by the range's transpose amount.
+    #[name = "Transpose"]
+    Transpose,
+}
+
+/// How a range uses its main pitch and its layers.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum PitchOrder {
+    /// Play all of them at once.
+    #[name = "Layer"]
+    Layer,
+    /// Play one of them per note, in order.
+    #[name = "Round Robin"]
+    Sequential,
+    #[name = "Random"]
+    Random,
+    /// Play a random one, but never the same one twice in a row.
+    #[name = "Random No Repeat"]
+    RandomNoRepeat,
+    /// Play a random one, picked in proportion to their weights.
+    #[name = "Weighted Random"]
+    Weighted,
+}
+
+/// What velocity a range sends its notes out with.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum VelocityMode {
+    #[name = "Pass Through"]
+    PassThrough,
+    #[name = "Fixed"]
+    Fixed,
+    /// Scale the range's velocity span to the output span.
+    #[name = "Rescale"]
+    Rescale,
+    /// Like `Rescale`, but bent by the velocity curve.
+    #[name = "Curve"]
+    Curve,
+}
+
+#[derive(Params)]
+pub struct LayerParams {
+    #[id = "layer_active"]
+    pub active: BoolParam,
+
+    #[id = "layer_pitch"]
+    pub pitch: IntParam,
+
+    #[id = "layer_weight"]
+    pub weight: FloatParam,
 }
 
 impl Default for VelocityMapperParams {
@@ -36,13 +582,106 @@ impl Default for VelocityMapperParams {
         Self {
             editor_state: editor::default_state(),
 
-            // how_many: IntParam::new(
-            //     "how many",
-            //     4,
-            //     IntRange::Linear { min: 1, max: 10 }
-            // ).non_automatable(),
+            how_many: IntParam::new(
+                "Number of Ranges",
+                4,
+                IntRange::Linear { min: 1, max: MAX_RANGES as i32 }
+            ).non_automatable(),
+
+            note_off_matching: EnumParam::new(
+                "Note-Off Matching",
+                NoteOffMatching::Lifo
+            ).non_automatable(),
+
+            overlap: EnumParam::new(
+                "Overlapping Ranges",
+                OverlapMode::First
+            ).non_automatable(),
+
+            unmatched: EnumParam::new(
+                "Unmatched Notes",
+                UnmatchedMode::Pass
+            ).non_automatable(),
+
+            seed: IntParam::new(
+                "Random Seed",
+                0,
+                IntRange::Linear { min: 0, max: 9999 }
+            ).non_automatable(),
+
+            scale: EnumParam::new(
+                "Scale",
+                ScaleType::Off
+            ),
+
+            scale_root: IntParam::new(
+                "Scale Root",
+                0,
+                IntRange::Linear { min: 0, max: SCALE_STEPS as i32 - 1 }
+            )
+                .with_value_to_string(Arc::new(|value| NOTE_NAMES[value as usize % SCALE_STEPS].to_string()))
+                .with_string_to_value(Arc::new(|string| {
+                    NOTE_NAMES
+                        .iter()
+                        .position(|name| name.eq_ignore_ascii_case(string.trim()))
+                        .map(|idx| idx as i32)
+                })),
+
+            scale_steps: (0..SCALE_STEPS).map(|_| ScaleStepParams::default()).collect(),
+
+            velocity_cc_mode: EnumParam::new(
+                "Velocity Controller",
+                VelocityCcMode::Off
+            ).non_automatable(),
+
+            velocity_cc: IntParam::new(
+                "Velocity CC Number",
+                1,
+                IntRange::Linear { min: 0, max: 127 }
+            ).non_automatable(),
+
+            velocity_cc_source: EnumParam::new(
+                "Velocity Controller Source",
+                VelocityCcSource::Input
+            ).non_automatable(),
+
+            choke_mode: EnumParam::new(
+                "Choke Mode",
+                ChokeMode::NoteOff
+            ).non_automatable(),
+
+            pedal_cc: IntParam::new(
+                "Pedal CC Number",
+                4,
+                IntRange::Linear { min: 0, max: 127 }
+            ).non_automatable(),
+
+            retrigger_ms: time_param("Retrigger Window", 0.0, 100.0),
+
+            retrigger_mode: EnumParam::new(
+                "Retrigger Mode",
+                RetriggerMode::DropSecond
+            ).non_automatable(),
+
+            crosstalk_ms: time_param("Crosstalk Window", 0.0, 100.0),
 
-            ranges: vec![RangeParams::default(), RangeParams::default(), RangeParams::default(), RangeParams::default()],
+            crosstalk_ratio: FloatParam::new(
+                "Crosstalk Ratio",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 }
+            )
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+
+            crosstalk_groups: (0..MAX_CROSSTALK_GROUPS).map(|_| CrosstalkGroupParams::default()).collect(),
+
+            // changing this changes the latency, which hosts don't expect during playback
+            combine_ms: time_param("Combine Window", 0.0, 50.0).non_automatable(),
+
+            combine_rules: (0..MAX_COMBINE_RULES).map(|_| CombineRuleParams::default()).collect(),
+
+            ranges: (0..MAX_RANGES).map(|_| RangeParams::default()).collect(),
         }
     }
 }
@@ -54,6 +693,16 @@ impl Default for RangeParams {
                 "Bypass",
                 true
             ),
+            action: EnumParam::new(
+                "Action",
+                RangeAction::Remap
+            ).non_automatable(),
+            trigger: EnumParam::new(
+                "Trigger",
+                Trigger::NoteOn
+            ).non_automatable(),
+            held_min: time_param("Held Time Minimum", 0.0, 10_000.0),
+            held_max: time_param("Held Time Maximum", 10_000.0, 10_000.0),
             range_min: IntParam::new(
                 "Velocity Minimum",
                 0,
@@ -64,11 +713,314 @@ impl Default for RangeParams {
                 127,
                 IntRange::Linear { min: 0, max: 127 }
             ),
+            key_min: IntParam::new(
+                "Input Key Minimum",
+                0,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            key_max: IntParam::new(
+                "Input Key Maximum",
+                127,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            input_channel: IntParam::new(
+                "Input Channel",
+                0,
+                IntRange::Linear { min: 0, max: 16 }
+            )
+                .with_value_to_string(channel_to_string("Any"))
+                .with_string_to_value(string_to_channel("Any"))
+                .non_automatable(),
+            pedal_min: IntParam::new(
+                "Pedal Minimum",
+                0,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            pedal_max: IntParam::new(
+                "Pedal Maximum",
+                127,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            roll: EnumParam::new(
+                "Roll Condition",
+                RollCondition::Any
+            ).non_automatable(),
+            roll_ms: time_param("Roll Interval", 120.0, 1000.0),
+            roll_hysteresis: FloatParam::new(
+                "Roll Hysteresis",
+                0.2,
+                FloatRange::Linear { min: 0.0, max: 1.0 }
+            )
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
             pitch: IntParam::new(
                 "Output Pitch",
                 60,
                 IntRange::Linear { min: 0, max: 127 }
             ),
+            weight: weight_param("Pitch Weight"),
+            probability: FloatParam::new(
+                "Probability",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 }
+            )
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            pitch_mode: EnumParam::new(
+                "Pitch Mode",
+                PitchMode::Absolute
+            ).non_automatable(),
+            quantize: BoolParam::new(
+                "Quantize",
+                true
+            ),
+            transpose: IntParam::new(
+                "Transpose",
+                0,
+                IntRange::Linear { min: -48, max: 48 }
+            )
+                .with_unit(" st"),
+            layers: (0..MAX_LAYERS).map(|_| LayerParams::default()).collect(),
+            pitch_order: EnumParam::new(
+                "Pitch Order",
+                PitchOrder::Layer
+            ).non_automatable(),
+            output_channel: IntParam::new(
+                "Output Channel",
+                0,
+                IntRange::Linear { min: 0, max: 16 }
+            )
+                .with_value_to_string(channel_to_string("Input"))
+                .with_string_to_value(string_to_channel("Input"))
+                .non_automatable(),
+            extra_channels: (0..MAX_EXTRA_CHANNELS).map(|_| ExtraChannelParams::default()).collect(),
+            velocity_mode: EnumParam::new(
+                "Velocity Mode",
+                VelocityMode::PassThrough
+            ).non_automatable(),
+            fixed_velocity: IntParam::new(
+                "Fixed Velocity",
+                100,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            out_velocity_min: IntParam::new(
+                "Output Velocity Minimum",
+                1,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            out_velocity_max: IntParam::new(
+                "Output Velocity Maximum",
+                127,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            velocity_curve: FloatParam::new(
+                "Velocity Curve",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 }
+            )
+                .with_step_size(0.01),
+            messages: (0..MAX_MESSAGES).map(|_| MessageParams::default()).collect(),
+            only_on_change: BoolParam::new(
+                "Only On Change",
+                false
+            ),
+            gate_mode: EnumParam::new(
+                "Gate Mode",
+                GateMode::Off
+            ).non_automatable(),
+            gate_unit: EnumParam::new(
+                "Gate Unit",
+                GateUnit::Milliseconds
+            ).non_automatable(),
+            gate_ms: time_param("Gate Length", 100.0, 10_000.0),
+            gate_note: EnumParam::new(
+                "Gate Note Value",
+                NoteValue::Sixteenth
+            ),
+            choke_group: IntParam::new(
+                "Choke Group",
+                0,
+                IntRange::Linear { min: 0, max: 16 }
+            )
+                .with_value_to_string(Arc::new(|value| {
+                    if value == 0 {
+                        String::from("None")
+                    } else {
+                        value.to_string()
+                    }
+                }))
+                .non_automatable(),
         }
     }
+}
+
+impl Default for LayerParams {
+    fn default() -> Self {
+        Self {
+            active: BoolParam::new(
+                "Layer",
+                false
+            ),
+            pitch: IntParam::new(
+                "Layer Pitch",
+                60,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            weight: weight_param("Layer Weight"),
+        }
+    }
+}
+
+impl Default for MessageParams {
+    fn default() -> Self {
+        Self {
+            kind: EnumParam::new(
+                "Message",
+                MessageKind::Off
+            ).non_automatable(),
+            number: IntParam::new(
+                "Message Number",
+                0,
+                IntRange::Linear { min: 0, max: 127 }
+            ).non_automatable(),
+            value: IntParam::new(
+                "Message Value",
+                127,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+        }
+    }
+}
+
+impl Default for ScaleStepParams {
+    fn default() -> Self {
+        Self {
+            active: BoolParam::new(
+                "Scale Step",
+                true
+            ),
+        }
+    }
+}
+
+impl Default for CrosstalkGroupParams {
+    fn default() -> Self {
+        Self {
+            pads: (0..MAX_GROUP_PADS).map(|_| PadParams::default()).collect(),
+        }
+    }
+}
+
+impl Default for CombineRuleParams {
+    fn default() -> Self {
+        Self {
+            active: BoolParam::new(
+                "Combine Rule",
+                false
+            ),
+            pitch_a: IntParam::new(
+                "Combine First Pitch",
+                38,
+                IntRange::Linear { min: 0, max: 127 }
+            ).non_automatable(),
+            pitch_b: IntParam::new(
+                "Combine Second Pitch",
+                40,
+                IntRange::Linear { min: 0, max: 127 }
+            ).non_automatable(),
+            output: IntParam::new(
+                "Combined Pitch",
+                37,
+                IntRange::Linear { min: 0, max: 127 }
+            ).non_automatable(),
+        }
+    }
+}
+
+impl Default for PadParams {
+    fn default() -> Self {
+        Self {
+            pitch: IntParam::new(
+                "Pad Pitch",
+                -1,
+                IntRange::Linear { min: -1, max: 127 }
+            )
+                .with_value_to_string(Arc::new(|value| {
+                    if value < 0 {
+                        "Off".to_string()
+                    } else {
+                        value.to_string()
+                    }
+                }))
+                .with_string_to_value(Arc::new(|string| {
+                    let string = string.trim();
+                    if string.eq_ignore_ascii_case("off") {
+                        Some(-1)
+                    } else {
+                        string.parse().ok()
+                    }
+                }))
+                .non_automatable(),
+        }
+    }
+}
+
+impl Default for ExtraChannelParams {
+    fn default() -> Self {
+        Self {
+            channel: IntParam::new(
+                "Extra Output Channel",
+                0,
+                IntRange::Linear { min: 0, max: 16 }
+            )
+                .with_value_to_string(channel_to_string("Off"))
+                .with_string_to_value(string_to_channel("Off"))
+                .non_automatable(),
+        }
+    }
+}
+
+/// How likely a pitch is picked with [`PitchOrder::Weighted`], relative to the others.
+fn weight_param(name: &str) -> FloatParam {
+    FloatParam::new(
+        name,
+        1.0,
+        FloatRange::Linear { min: 0.0, max: 10.0 }
+    )
+        .with_step_size(0.1)
+}
+
+/// A time in milliseconds, from 0 to `max`.
+fn time_param(name: &str, default: f32, max: f32) -> FloatParam {
+    FloatParam::new(
+        name,
+        default,
+        FloatRange::Skewed { min: 0.0, max, factor: FloatRange::skew_factor(-2.0) }
+    )
+        .with_unit(" ms")
+        .with_value_to_string(formatters::v2s_f32_rounded(0))
+}
+
+/// Displays MIDI channel parameters as 1 to 16, with 0 meaning `none`.
+fn channel_to_string(none: &'static str) -> Arc<dyn Fn(i32) -> String + Send + Sync> {
+    Arc::new(move |value| {
+        if value == 0 {
+            none.to_string()
+        } else {
+            value.to_string()
+        }
+    })
+}
+
+fn string_to_channel(none: &'static str) -> Arc<dyn Fn(&str) -> Option<i32> + Send + Sync> {
+    Arc::new(move |string| {
+        let string = string.trim();
+        if string.eq_ignore_ascii_case(none) {
+            Some(0)
+        } else {
+            string.parse().ok()
+        }
+    })
 }
\ No newline at end of file
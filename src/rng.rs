@@ -0,0 +1,93 @@
+/// A small xorshift random number generator. It never allocates, so it can be used on the audio
+/// thread, and the same seed always produces the same sequence.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.reseed(seed);
+        rng
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        // scramble the seed so small seeds don't start out with mostly zero bits, xorshift would
+        // also get stuck on a state of 0
+        self.state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A random number in `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A random index into `weights`, where each index is picked in proportion to its weight.
+    /// `weights` must not be empty.
+    pub fn weighted(&mut self, weights: &[f32]) -> usize {
+        let total: f32 = weights.iter().map(|weight| weight.max(0.0)).sum();
+        if total <= 0.0 {
+            return self.below(weights.len());
+        }
+
+        let mut target = self.next_f32() * total;
+        for (idx, weight) in weights.iter().enumerate() {
+            target -= weight.max(0.0);
+            if target < 0.0 {
+                return idx;
+            }
+        }
+        weights.len() - 1
+    }
+
+    /// A random index in `0..len`, `len` must not be 0.
+    pub fn below(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reseeding_restarts_the_sequence() {
+        let mut rng = Rng::new(7);
+        let first: Vec<u64> = (0..10).map(|_| rng.next_u64()).collect();
+
+        rng.reseed(7);
+        let second: Vec<u64> = (0..10).map(|_| rng.next_u64()).collect();
+        assert_eq!(first, second);
+
+        rng.reseed(8);
+        let other: Vec<u64> = (0..10).map(|_| rng.next_u64()).collect();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn seed_zero_does_not_get_stuck() {
+        let mut rng = Rng::new(0);
+        assert!((0..10).any(|_| rng.next_u64() != 0));
+    }
+
+    #[test]
+    fn next_f32_stays_below_one() {
+        let mut rng = Rng::new(3);
+        assert!((0..1000).map(|_| rng.next_f32()).all(|value| (0.0..1.0).contains(&value)));
+    }
+
+    #[test]
+    fn weighted_never_picks_zero_weights() {
+        let mut rng = Rng::new(5);
+        assert!((0..1000).all(|_| rng.weighted(&[0.0, 1.0, 0.0]) == 1));
+        // all zero weights fall back to an even pick
+        assert!((0..1000).all(|_| rng.weighted(&[0.0, 0.0]) < 2));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::params::PitchOrder;
+use crate::rng::Rng;
+
+/// Remembers which of its output pitches a range played last, to cycle through them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RoundRobin {
+    next: usize,
+    last: Option<usize>,
+}
+
+impl RoundRobin {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Which of the output pitches to play next, given their weights. `weights` must not be empty.
+    pub fn pick(&mut self, order: PitchOrder, weights: &[f32], rng: &mut Rng) -> usize {
+        let len = weights.len();
+        let pick = match order {
+            PitchOrder::Layer | PitchOrder::Sequential => self.next % len,
+            PitchOrder::Random => rng.below(len),
+            PitchOrder::RandomNoRepeat => match self.last {
+                // pick one of the others and skip over the last one
+                Some(last) if len > 1 && last < len => {
+                    let pick = rng.below(len - 1);
+                    if pick >= last { pick + 1 } else { pick }
+                },
+                _ => rng.below(len),
+            },
+            PitchOrder::Weighted => rng.weighted(weights),
+        };
+
+        self.next = pick + 1;
+        self.last = Some(pick);
+        pick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_cycles_and_resets() {
+        let mut rng = Rng::new(0);
+        let mut round_robin = RoundRobin::default();
+        let weights = [1.0; 3];
+
+        let picks: Vec<usize> = (0..5).map(|_| round_robin.pick(PitchOrder::Sequential, &weights, &mut rng)).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1]);
+
+        round_robin.reset();
+        assert_eq!(round_robin.pick(PitchOrder::Sequential, &weights, &mut rng), 0);
+    }
+
+    #[test]
+    fn random_no_repeat_never_repeats() {
+        let mut rng = Rng::new(1);
+        let mut round_robin = RoundRobin::default();
+        let weights = [1.0; 2];
+
+        let mut last = round_robin.pick(PitchOrder::RandomNoRepeat, &weights, &mut rng);
+        for _ in 0..100 {
+            let pick = round_robin.pick(PitchOrder::RandomNoRepeat, &weights, &mut rng);
+            assert_ne!(pick, last);
+            last = pick;
+        }
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_pitches() {
+        let picks = |seed| {
+            let mut rng = Rng::new(seed);
+            let mut round_robin = RoundRobin::default();
+            (0..20)
+                .map(|_| round_robin.pick(PitchOrder::Random, &[1.0; 4], &mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(picks(42), picks(42));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::params::ScaleType;
+
+/// The number of steps in an octave, the length of a custom scale.
+pub const SCALE_STEPS: usize = 12;
+
+/// Names of the steps of a scale, relative to its root.
+pub const STEP_NAMES: [&str; SCALE_STEPS] = ["1", "b2", "2", "b3", "3", "4", "#4", "5", "b6", "6", "b7", "7"];
+
+pub const NOTE_NAMES: [&str; SCALE_STEPS] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// The steps of a scale as a bit mask, bit 0 is the root. `ScaleType::Custom` and
+/// `ScaleType::Off` have no fixed steps and return every step.
+pub fn scale_mask(scale: ScaleType) -> u16 {
+    let steps: &[u8] = match scale {
+        ScaleType::Off | ScaleType::Custom => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        ScaleType::Major => &[0, 2, 4, 5, 7, 9, 11],
+        ScaleType::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+        ScaleType::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+        ScaleType::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+        ScaleType::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+        ScaleType::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+        ScaleType::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+        ScaleType::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+        ScaleType::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+        ScaleType::MajorPentatonic => &[0, 2, 4, 7, 9],
+        ScaleType::MinorPentatonic => &[0, 3, 5, 7, 10],
+        ScaleType::Blues => &[0, 3, 5, 6, 7, 10],
+    };
+
+    steps.iter().fold(0, |mask, step| mask | 1 << step)
+}
+
+/// Snap a pitch to the nearest pitch in the scale, preferring the lower one when two are equally
+/// close. Pitches are left alone if the scale has no steps at all.
+pub fn quantize(pitch: u8, root: u8, mask: u16) -> u8 {
+    if mask & 0xFFF == 0 {
+        return pitch;
+    }
+
+    let in_scale = |pitch: i32| {
+        let step = (pitch - root as i32).rem_euclid(SCALE_STEPS as i32);
+        mask & (1 << step) != 0
+    };
+
+    for distance in 0..=SCALE_STEPS as i32 {
+        for candidate in [pitch as i32 - distance, pitch as i32 + distance] {
+            if (0..=127).contains(&candidate) && in_scale(candidate) {
+                return candidate as u8;
+            }
+        }
+    }
+    pitch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pitches_in_the_scale_stay() {
+        let mask = scale_mask(ScaleType::Major);
+        for pitch in [60, 62, 64, 65, 67, 69, 71, 72] {
+            assert_eq!(quantize(pitch, 0, mask), pitch);
+        }
+    }
+
+    #[test]
+    fn ties_snap_down() {
+        // C# is between C and D in C major
+        assert_eq!(quantize(61, 0, scale_mask(ScaleType::Major)), 60);
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_step() {
+        // C major pentatonic has no F, E is closer than G
+        assert_eq!(quantize(65, 0, scale_mask(ScaleType::MajorPentatonic)), 64);
+        // A# is closer to A than to C
+        assert_eq!(quantize(70, 0, scale_mask(ScaleType::MajorPentatonic)), 69);
+    }
+
+    #[test]
+    fn the_root_shifts_the_scale() {
+        // D major has F#, not F
+        assert_eq!(quantize(65, 2, scale_mask(ScaleType::Major)), 64);
+        assert_eq!(quantize(66, 2, scale_mask(ScaleType::Major)), 66);
+    }
+
+    #[test]
+    fn stays_in_midi_range() {
+        let mask = scale_mask(ScaleType::MinorPentatonic);
+        assert!(quantize(127, 0, mask) <= 127);
+        assert_eq!(quantize(0, 0, mask), 0);
+    }
+
+    #[test]
+    fn empty_scale_leaves_pitches_alone() {
+        assert_eq!(quantize(61, 0, 0), 61);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::timed_queue::{Timed, TimedQueue};
+
+/// The maximum number of NoteOffs that can wait to be sent at the same time.
+pub const MAX_SCHEDULED: usize = 1024;
+
+/// A NoteOff the plugin generates itself, at a later sample.
+#[derive(Debug, Clone, Copy)]
+pub struct ScheduledNoteOff {
+    /// The sample this NoteOff should be sent at, counted from when the plugin was created.
+    pub due: u64,
+    pub voice_id: Option<i32>,
+    pub channel: u8,
+    pub note: u8,
+    /// The range that produced the note.
+    pub range: Option<usize>,
+}
+
+impl Timed for ScheduledNoteOff {
+    fn due(&self) -> u64 {
+        self.due
+    }
+}
+
+/// Keeps NoteOffs until they are due.
+pub type Scheduler = TimedQueue<ScheduledNoteOff, MAX_SCHEDULED>;
+
+impl Scheduler {
+    /// Remove and return the earliest NoteOff scheduled for this note.
+    pub fn cancel(&mut self, channel: u8, note: u8) -> Option<ScheduledNoteOff> {
+        self.take_first(|note_off| note_off.channel == channel && note_off.note == note)
+    }
+}
@@ -0,0 +1,69 @@
+/// Something that happens at a later sample.
+pub trait Timed {
+    /// The sample this is due at, counted from when the plugin was created.
+    fn due(&self) -> u64;
+}
+
+/// Keeps items until they are due, possibly several buffers later. Like the
+/// [`NoteStack`](crate::note_stack::NoteStack), this allocates all of its `CAPACITY` up front, so
+/// it is safe to use on the audio thread. Items that are due at the same sample come out in the
+/// order they went in.
+pub struct TimedQueue<T: Timed, const CAPACITY: usize> {
+    items: Vec<T>,
+}
+
+impl<T: Timed, const CAPACITY: usize> Default for TimedQueue<T, CAPACITY> {
+    fn default() -> Self {
+        Self {
+            items: Vec::with_capacity(CAPACITY),
+        }
+    }
+}
+
+impl<T: Timed, const CAPACITY: usize> TimedQueue<T, CAPACITY> {
+    /// Returns `false` if the queue is full, the caller then has to handle the item right away.
+    pub fn push(&mut self, item: T) -> bool {
+        if self.items.len() >= CAPACITY {
+            return false;
+        }
+
+        self.items.push(item);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The due time of the latest item in the queue.
+    pub fn last_due(&self) -> Option<u64> {
+        self.items.iter().map(Timed::due).max()
+    }
+
+    /// Remove and return the earliest item that is due before `until`.
+    pub fn pop_due(&mut self, until: u64) -> Option<T> {
+        self.take_first(|item| item.due() < until)
+    }
+
+    /// Remove and return the earliest item for which `matches` returns `true`.
+    pub fn take_first(&mut self, mut matches: impl FnMut(&T) -> bool) -> Option<T> {
+        let idx = self.items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| matches(item))
+            .min_by_key(|(_, item)| item.due())
+            .map(|(idx, _)| idx)?;
+
+        Some(self.items.remove(idx))
+    }
+
+    /// Remove all items for which `remove` returns `true`.
+    pub fn remove_where(&mut self, mut remove: impl FnMut(&T) -> bool) {
+        self.items.retain(|item| !remove(item));
+    }
+
+    /// Remove all items, e.g. to handle them right away when playback stops.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.items.drain(..)
+    }
+}